@@ -0,0 +1,85 @@
+use crate::mock::MockMode;
+use crate::proto::invoice::{invoice_client::InvoiceClient, ReceivablesRequest};
+use std::error::Error;
+
+/// Run the given CLI command against the running service
+/// Returns false if there is no such command
+pub async fn run(args: &[String]) -> Result<bool, Box<dyn Error>> {
+  match args.first().map(|a| a.as_str()) {
+    Some("receivables") => {
+      receivables(args.iter().any(|a| a == "--overdue")).await?;
+      Ok(true)
+    }
+    Some("mock-szamlazzhu") => {
+      let addr = args
+        .get(1)
+        .filter(|a| !a.starts_with("--"))
+        .map(|a| a.as_str())
+        .unwrap_or("127.0.0.1:8090");
+      // --error=CODE answers every request with the given error code
+      // --malformed answers every request with a broken body
+      // --lost-response does the first request, but loses its answer
+      let mode = match args
+        .iter()
+        .find(|a| a.starts_with("--"))
+        .map(|a| a.as_str())
+      {
+        Some("--malformed") => MockMode::Malformed,
+        Some("--lost-response") => MockMode::LostResponse,
+        Some(a) if a.starts_with("--error=") => {
+          MockMode::Error(a["--error=".len()..].parse()?, "Mock hiba".to_string())
+        }
+        _ => MockMode::Success,
+      };
+      mock_szamlazzhu(addr, mode).await?;
+      Ok(true)
+    }
+    _ => Ok(false),
+  }
+}
+
+/// Print unpaid invoices grouped by customer
+/// With --overdue only the invoices past their due date
+async fn receivables(overdue_only: bool) -> Result<(), Box<dyn Error>> {
+  let addr = std::env::var("SERVICE_ADDR_INVOICE").unwrap_or_else(|_| "[::1]:50060".into());
+  let mut client = InvoiceClient::connect(format!("http://{}", addr)).await?;
+
+  let res = client
+    .get_receivables(ReceivablesRequest {
+      overdue_only,
+      date: String::default(),
+    })
+    .await?
+    .into_inner();
+
+  for customer in res.customers {
+    println!(
+      "{} ({}) kintlévőség: {} Ft, lejárt: {} Ft",
+      customer.name, customer.tax_number, customer.outstanding, customer.overdue
+    );
+    for r in customer.receivables {
+      println!(
+        "  {} határidő: {} összeg: {} Ft késés: {} nap",
+        r.invoice_id, r.payment_duedate, r.outstanding, r.days_overdue
+      );
+    }
+  }
+  println!(
+    "Összesen kintlévőség: {} Ft, lejárt: {} Ft",
+    res.total_outstanding, res.total_overdue
+  );
+
+  Ok(())
+}
+
+/// Run the mock szamlazz.hu server till Ctrl+C
+/// Point the service to it by INVOICE_AGENT_URL
+async fn mock_szamlazzhu(addr: &str, mode: MockMode) -> Result<(), Box<dyn Error>> {
+  let (addr, mock) = crate::mock::start(addr.parse()?)?;
+  mock.set_mode(mode);
+  println!("Mock szamlazz.hu fut: http://{}/", addr);
+  tokio::signal::ctrl_c().await?;
+  println!("Kiállított számlák: {}", mock.invoices().len());
+  println!("Kiállított nyugták: {}", mock.receipts().len());
+  Ok(())
+}
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
@@ -11,13 +11,13 @@ pub enum FileError {
   NotFound,
 }
 
-impl ToString for FileError {
-  fn to_string(&self) -> String {
+impl std::fmt::Display for FileError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
-      FileError::DecodeError => "Decode error".into(),
-      // FileError::EncodeError => "Encode error".into(),
-      FileError::SaveError(e) => format!("Save error {}", e),
-      FileError::NotFound => "A megadott file nem található!".into(),
+      FileError::DecodeError => write!(f, "Decode error"),
+      // FileError::EncodeError => write!(f, "Encode error"),
+      FileError::SaveError(e) => write!(f, "Save error {}", e),
+      FileError::NotFound => write!(f, "A megadott file nem található!"),
     }
   }
 }
@@ -52,10 +52,22 @@ pub async fn save_file(bytes: Vec<u8>, path: PathBuf) -> Result<(), FileError> {
   Ok(())
 }
 
-pub async fn load_invoice_base64(id: &str) -> Result<String, FileError> {
-  let id = id.to_owned();
+/// Path of the PDF of the given invoice or receipt number
+fn pdf_path(folder: &Path, id: &str) -> PathBuf {
+  folder.join(format!("{}.pdf", id))
+}
+
+pub async fn save_invoice_pdf(folder: &Path, id: &str, pdf: &[u8]) -> Result<(), FileError> {
+  save_file(pdf.to_vec(), pdf_path(folder, id)).await
+}
+
+pub async fn save_receipt_pdf(folder: &Path, id: &str, pdf_base64: &str) -> Result<(), FileError> {
+  let bytes = base64_decode(&pdf_base64.replace("\n", ""))?;
+  save_invoice_pdf(folder, id, &bytes).await
+}
 
-  let mut file = File::open(format!("data/{}/{}.pdf", crate::PDF_FOLDER_NAME, id))
+pub async fn load_invoice_base64(folder: &Path, id: &str) -> Result<String, FileError> {
+  let mut file = File::open(pdf_path(folder, id))
     .await
     .map_err(|_| FileError::NotFound)?;
 
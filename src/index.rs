@@ -0,0 +1,372 @@
+use crate::invoice::{Invoice, InvoiceKind, InvoiceStatus, PaymentMethod};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use packman::VecPack;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use uuid::Uuid;
+
+/// Invoice search filter
+/// Empty fields match every invoice
+#[derive(Debug, Default, Clone)]
+pub struct InvoiceFilter {
+  // Invoice date range, both inclusive
+  pub date_from: Option<NaiveDate>,
+  pub date_to: Option<NaiveDate>,
+  pub purchase_id: Option<String>,
+  pub invoice_id: Option<String>,
+  // Customer tax number, or part of its name
+  pub customer: Option<String>,
+  pub statuses: Vec<InvoiceStatus>,
+  pub created_by: Option<u32>,
+  pub payment_methods: Vec<PaymentMethod>,
+}
+
+impl InvoiceFilter {
+  pub fn matches(&self, i: &Invoice) -> bool {
+    let date = NaiveDate::parse_from_str(&i.header.date_created, "%Y-%m-%d").ok();
+    let customer = |c: &String| {
+      i.customer.tax_number == *c || i.customer.name.to_lowercase().contains(&c.to_lowercase())
+    };
+    self
+      .date_from
+      .is_none_or(|from| date.is_some_and(|d| d >= from))
+      && self.date_to.is_none_or(|to| date.is_some_and(|d| d <= to))
+      && self
+        .purchase_id
+        .as_ref()
+        .is_none_or(|p| &i.purchase_id == p)
+      && self
+        .invoice_id
+        .as_ref()
+        .is_none_or(|id| i.invoice_id.as_ref() == Some(id))
+      && self.customer.as_ref().is_none_or(customer)
+      && (self.statuses.is_empty() || self.statuses.contains(&i.status))
+      && self.created_by.is_none_or(|c| i.created_by == c)
+      && (self.payment_methods.is_empty()
+        || self.payment_methods.contains(&i.header.payment_method))
+  }
+}
+
+/// Place of an invoice in the search order
+/// Invoices are ordered by their creation time, then by their ID,
+/// as the store order changes when it is loaded again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+  created_at: DateTime<Utc>,
+  id: Uuid,
+}
+
+impl Cursor {
+  fn of(i: &Invoice) -> Self {
+    Cursor {
+      created_at: i.created_at,
+      id: i.id,
+    }
+  }
+  /// Parse cursor given by its Display format
+  pub fn parse(s: &str) -> Option<Self> {
+    let (created_at, id) = s.split_once('_')?;
+    Some(Cursor {
+      created_at: DateTime::parse_from_rfc3339(created_at)
+        .ok()?
+        .with_timezone(&Utc),
+      id: Uuid::parse_str(id).ok()?,
+    })
+  }
+}
+
+impl std::fmt::Display for Cursor {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}_{}",
+      self.created_at.to_rfc3339_opts(SecondsFormat::Nanos, true),
+      self.id.to_simple()
+    )
+  }
+}
+
+/// Secondary indices of the invoice store.
+/// Invoice store is append only, so we index invoices
+/// by their position in the store.
+#[derive(Debug, Default)]
+pub struct InvoiceIndex {
+  // Positions in search order
+  ordered: BTreeMap<Cursor, usize>,
+  // Search order of each position
+  cursors: Vec<Cursor>,
+  by_purchase_id: HashMap<String, Vec<usize>>,
+  by_invoice_id: HashMap<String, usize>,
+  by_tax_number: HashMap<String, Vec<usize>>,
+  by_created_by: HashMap<u32, Vec<usize>>,
+  // Positions of the invoices without invoice ID yet
+  pending: Vec<usize>,
+  // Number of indexed invoices
+  len: usize,
+}
+
+impl InvoiceIndex {
+  pub fn new() -> Self {
+    InvoiceIndex::default()
+  }
+
+  /// Index the new invoices of the store,
+  /// and the invoice IDs given since the last refresh
+  pub fn refresh(&mut self, store: &VecPack<Invoice>) {
+    let invoices = store.as_vec();
+    let pending = std::mem::take(&mut self.pending);
+    for position in pending.into_iter().chain(self.len..invoices.len()) {
+      let i = invoices[position].unpack();
+      if position >= self.len {
+        let cursor = Cursor::of(i);
+        self.ordered.insert(cursor, position);
+        self.cursors.push(cursor);
+        self
+          .by_purchase_id
+          .entry(i.purchase_id.clone())
+          .or_default()
+          .push(position);
+        if !i.customer.tax_number.is_empty() {
+          self
+            .by_tax_number
+            .entry(i.customer.tax_number.clone())
+            .or_default()
+            .push(position);
+        }
+        self
+          .by_created_by
+          .entry(i.created_by)
+          .or_default()
+          .push(position);
+      }
+      match &i.invoice_id {
+        Some(invoice_id) => {
+          self.by_invoice_id.insert(invoice_id.clone(), position);
+        }
+        // Cancelled invoices will never get invoice ID
+        None if i.status != InvoiceStatus::Cancelled => self.pending.push(position),
+        None => (),
+      }
+    }
+    self.len = invoices.len();
+  }
+
+  /// Invoices of the given purchase in creation order,
+  /// including its corrective and storno invoices
+  pub fn purchase_invoices(&self, store: &VecPack<Invoice>, purchase_id: &str) -> Vec<Invoice> {
+    let invoices = store.as_vec();
+    let mut positions = self
+      .by_purchase_id
+      .get(purchase_id)
+      .cloned()
+      .unwrap_or_default();
+    positions.sort_unstable_by_key(|p| self.cursors[*p]);
+    positions
+      .into_iter()
+      .map(|p| invoices[p].unpack().clone())
+      .collect()
+  }
+
+  /// Check if the purchase has any valid normal or final invoice
+  pub fn has_valid_invoice(&self, store: &VecPack<Invoice>, purchase_id: &str) -> bool {
+    let invoices = store.as_vec();
+    self
+      .by_purchase_id
+      .get(purchase_id)
+      .is_some_and(|positions| {
+        positions.iter().any(|p| {
+          let i = invoices[*p].unpack();
+          matches!(i.kind, InvoiceKind::Normal | InvoiceKind::Final) && i.is_valid()
+        })
+      })
+  }
+
+  /// Candidate positions of the given filter in search order
+  /// None if no index can be used
+  fn candidates(&self, filter: &InvoiceFilter) -> Option<Vec<usize>> {
+    let mut res: Option<HashSet<usize>> = None;
+    let mut narrow = |positions: Vec<usize>| {
+      res = Some(match res.take() {
+        Some(prev) => positions.into_iter().filter(|p| prev.contains(p)).collect(),
+        None => positions.into_iter().collect(),
+      });
+    };
+    if let Some(invoice_id) = &filter.invoice_id {
+      narrow(
+        self
+          .by_invoice_id
+          .get(invoice_id)
+          .copied()
+          .into_iter()
+          .collect(),
+      );
+    }
+    if let Some(purchase_id) = &filter.purchase_id {
+      narrow(
+        self
+          .by_purchase_id
+          .get(purchase_id)
+          .cloned()
+          .unwrap_or_default(),
+      );
+    }
+    if let Some(created_by) = &filter.created_by {
+      narrow(
+        self
+          .by_created_by
+          .get(created_by)
+          .cloned()
+          .unwrap_or_default(),
+      );
+    }
+    // Customer can be a tax number or part of a name,
+    // so we can only use the index, if it is a known tax number
+    if let Some(positions) = filter
+      .customer
+      .as_ref()
+      .and_then(|c| self.by_tax_number.get(c))
+    {
+      narrow(positions.clone());
+    }
+    res.map(|positions| {
+      let mut positions = positions.into_iter().collect::<Vec<usize>>();
+      positions.sort_unstable_by_key(|p| self.cursors[*p]);
+      positions
+    })
+  }
+
+  /// Invoices of the given filter, newest first.
+  /// Cursor is the one of the last invoice of the previous page.
+  /// Returns the page and the cursor of the next page, if there is any.
+  pub fn search(
+    &self,
+    store: &VecPack<Invoice>,
+    filter: &InvoiceFilter,
+    cursor: Option<Cursor>,
+    limit: usize,
+  ) -> (Vec<Invoice>, Option<Cursor>) {
+    let invoices = store.as_vec();
+    let before = |c: &Cursor| cursor.is_none_or(|cursor| *c < cursor);
+    let positions: Box<dyn Iterator<Item = usize>> = match self.candidates(filter) {
+      Some(positions) => Box::new(
+        positions
+          .into_iter()
+          .rev()
+          .filter(move |p| before(&self.cursors[*p])),
+      ),
+      None => Box::new(
+        self
+          .ordered
+          .iter()
+          .rev()
+          .filter(move |(c, _)| before(c))
+          .map(|(_, p)| *p),
+      ),
+    };
+
+    let mut page: Vec<(usize, Invoice)> = positions
+      .filter(|p| filter.matches(invoices[*p].unpack()))
+      .take(limit + 1)
+      .map(|p| (p, invoices[p].unpack().clone()))
+      .collect();
+
+    let next_cursor = if page.len() > limit {
+      page.truncate(limit);
+      page.last().map(|(p, _)| self.cursors[*p])
+    } else {
+      None
+    };
+
+    (page.into_iter().map(|(_, i)| i).collect(), next_cursor)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::invoice::{Customer, Header, InvoiceObject};
+  #[test]
+  fn test_filter_matches() {
+    let date = |d| NaiveDate::from_ymd_opt(2021, 3, d).unwrap();
+    let invoice: Invoice = InvoiceObject {
+      cart_id: "p1".into(),
+      customer: Customer {
+        name: "Kert Kft.".into(),
+        tax_number: "1234".into(),
+        ..Customer::default()
+      },
+      header: Header::new(date(10), date(10), date(18), PaymentMethod::Transfer),
+      ..InvoiceObject::default()
+    }
+    .into();
+
+    assert!(InvoiceFilter::default().matches(&invoice));
+    let filter = InvoiceFilter {
+      date_from: Some(date(1)),
+      date_to: Some(date(10)),
+      customer: Some("kert".into()),
+      statuses: vec![InvoiceStatus::Queued],
+      payment_methods: vec![PaymentMethod::Transfer],
+      ..InvoiceFilter::default()
+    };
+    assert!(filter.matches(&invoice));
+    let filter = InvoiceFilter {
+      date_to: Some(date(9)),
+      ..InvoiceFilter::default()
+    };
+    assert!(!filter.matches(&invoice));
+    let filter = InvoiceFilter {
+      purchase_id: Some("p2".into()),
+      ..InvoiceFilter::default()
+    };
+    assert!(!filter.matches(&invoice));
+  }
+
+  #[test]
+  fn test_search_order() {
+    let dir = std::env::temp_dir().join(format!("invoice_index_{}", Uuid::new_v4()));
+    let mut store: VecPack<Invoice> = VecPack::load_or_init(dir).unwrap();
+    // Store order differs from the creation order, as after a restart
+    let now = Utc::now();
+    let mut ids = Vec::new();
+    for minutes in &[2, 0, 3, 1] {
+      let mut invoice: Invoice = InvoiceObject {
+        internal_id: Uuid::new_v4(),
+        cart_id: "p1".into(),
+        ..InvoiceObject::default()
+      }
+      .into();
+      invoice.created_at = now + chrono::Duration::minutes(*minutes);
+      ids.push((invoice.created_at, invoice.id));
+      store.insert(invoice).unwrap();
+    }
+    ids.sort();
+    ids.reverse();
+    let mut index = InvoiceIndex::new();
+    index.refresh(&store);
+
+    for filter in &[
+      InvoiceFilter::default(),
+      InvoiceFilter {
+        purchase_id: Some("p1".into()),
+        ..InvoiceFilter::default()
+      },
+    ] {
+      let mut found = Vec::new();
+      let mut cursor = None;
+      loop {
+        let (page, next) = index.search(&store, filter, cursor, 3);
+        found.extend(page.iter().map(|i| (i.created_at, i.id)));
+        match next {
+          Some(c) => cursor = Some(Cursor::parse(&c.to_string()).unwrap()),
+          None => break,
+        }
+      }
+      assert_eq!(found, ids);
+    }
+
+    let purchase = index.purchase_invoices(&store, "p1");
+    assert_eq!(purchase.first().unwrap().id, ids[3].1);
+    assert_eq!(purchase.last().unwrap().id, ids[0].1);
+    assert!(Cursor::parse("12").is_none());
+  }
+}
@@ -7,6 +7,18 @@ use uuid::Uuid;
 #[tonic::async_trait]
 pub trait InvoiceAgent {
   async fn create_invoice(&self, data: InvoiceObject) -> Result<InvoiceSummary, AgentError>;
+  /// Find an already issued invoice by its order number
+  /// Returns None if there is no invoice with the given order number
+  async fn find_invoice(&self, order_number: &str) -> Result<Option<InvoiceSummary>, AgentError>;
+  /// Find an already issued storno invoice by the order number of its request
+  /// Returns None if there is no storno with the given order number
+  async fn find_storno(&self, order_number: &str) -> Result<Option<InvoiceSummary>, AgentError>;
+  /// Cancel an issued invoice by a storno invoice
+  /// Returns the storno invoice
+  async fn cancel_invoice(&self, data: InvoiceObject) -> Result<InvoiceSummary, AgentError>;
+  /// Register a payment of an issued invoice
+  /// Added to its previous payments
+  async fn register_payment(&self, invoice_id: &str, payment: Payment) -> Result<(), AgentError>;
 }
 
 #[derive(Debug)]
@@ -14,13 +26,273 @@ pub enum AgentError {
   DataError(String),
   // ServiceError,
   InternalError(String),
+  // Error reported by the invoice provider
+  // with its own error code
+  ProviderError(i32, String),
+  // Provider rejected the agent key
+  AuthError(i32, String),
+  // Invoice or receipt is not found by the provider
+  NotFound(i32, String),
+  // Order number or call ID is already used
+  Duplicate(i32, String),
+  // Provider rejected the customer tax number
+  InvalidTaxNumber(i32, String),
+  // Network error, timeout or provider side (5xx) error
+  ConnectionError(String),
+}
+
+impl AgentError {
+  /// Transient errors can be retried later,
+  /// other errors are permanent
+  pub fn is_retryable(&self) -> bool {
+    matches!(self, AgentError::ConnectionError(_))
+  }
+  /// Provider error code if there is any
+  pub fn error_code(&self) -> Option<i32> {
+    match self {
+      AgentError::ProviderError(code, _)
+      | AgentError::AuthError(code, _)
+      | AgentError::NotFound(code, _)
+      | AgentError::Duplicate(code, _)
+      | AgentError::InvalidTaxNumber(code, _) => Some(*code),
+      _ => None,
+    }
+  }
+}
+
+impl std::fmt::Display for AgentError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      AgentError::DataError(msg) => write!(f, "Data error: {}", msg),
+      AgentError::InternalError(msg) => write!(f, "Internal error: {}", msg),
+      AgentError::ProviderError(code, msg) => write!(f, "Provider error ({}): {}", code, msg),
+      AgentError::AuthError(code, msg) => write!(f, "Authentication error ({}): {}", code, msg),
+      AgentError::NotFound(code, msg) => write!(f, "Not found ({}): {}", code, msg),
+      AgentError::Duplicate(code, msg) => write!(f, "Duplicate ({}): {}", code, msg),
+      AgentError::InvalidTaxNumber(code, msg) => {
+        write!(f, "Invalid tax number ({}): {}", code, msg)
+      }
+      AgentError::ConnectionError(msg) => write!(f, "Connection error: {}", msg),
+    }
+  }
 }
 
 #[derive(Debug)]
 pub struct InvoiceSummary {
   pub invoice_id: String,
-  pub pdf_base64: String,
-  pub has_error: bool,
+  // None if the agent sent a broken PDF
+  pub pdf: Option<Vec<u8>>,
+  // Outstanding amount if the agent reports it
+  pub outstanding: Option<i32>,
+  // Online view of the invoice for the customer, if the agent reports it
+  pub customer_url: Option<String>,
+}
+
+/// Payment registered against an issued invoice
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Payment {
+  pub date: NaiveDate,
+  pub amount: i32,
+  pub payment_method: PaymentMethod,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl Payment {
+  pub fn new(date: NaiveDate, amount: i32, payment_method: PaymentMethod, created_by: u32) -> Self {
+    Payment {
+      date,
+      amount,
+      payment_method,
+      created_by,
+      created_at: Utc::now(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum InvoiceStatus {
+  // Invoice request is saved and waiting for the processor
+  #[default]
+  Queued,
+  // Invoice request is under submission to the invoice agent
+  Submitting,
+  // Invoice is issued and its PDF is saved
+  Issued,
+  // Invoice is issued, but its PDF is not saved
+  PdfMissing,
+  // Invoice agent failed to issue the invoice
+  Failed,
+  // Invoice request is cancelled before it was issued
+  Cancelled,
+  // Issued invoice is cancelled by a storno invoice
+  Stornoed,
+}
+
+impl InvoiceStatus {
+  /// Check whether the status can be changed to the given next status
+  pub fn can_transition_to(&self, next: &InvoiceStatus) -> bool {
+    use InvoiceStatus::*;
+    matches!(
+      (self, next),
+      (Queued, Submitting)
+        | (Queued, Failed)
+        | (Queued, Cancelled)
+        | (Submitting, Queued)
+        | (Submitting, Issued)
+        | (Submitting, PdfMissing)
+        | (Submitting, Failed)
+        | (PdfMissing, Issued)
+        | (Issued, Stornoed)
+        | (Failed, Queued)
+        | (Failed, Cancelled)
+    )
+  }
+}
+
+impl std::fmt::Display for InvoiceStatus {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      InvoiceStatus::Queued => write!(f, "Sorban áll"),
+      InvoiceStatus::Submitting => write!(f, "Beküldés alatt"),
+      InvoiceStatus::Issued => write!(f, "Kiállítva"),
+      InvoiceStatus::PdfMissing => write!(f, "Kiállítva, PDF hiányzik"),
+      InvoiceStatus::Failed => write!(f, "Sikertelen"),
+      InvoiceStatus::Cancelled => write!(f, "Visszavonva"),
+      InvoiceStatus::Stornoed => write!(f, "Sztornózva"),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FailureKind {
+  DataError,
+  InternalError,
+  ProviderError,
+  ConnectionError,
+  PdfError,
+}
+
+/// Reason of the last failed invoice agent attempt
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Failure {
+  pub kind: FailureKind,
+  pub message: String,
+  // Invoice provider error code if there is any
+  pub error_code: Option<i32>,
+  pub created_at: DateTime<Utc>,
+}
+
+impl From<&AgentError> for Failure {
+  fn from(e: &AgentError) -> Self {
+    let kind = match e {
+      AgentError::DataError(_) => FailureKind::DataError,
+      AgentError::InternalError(_) => FailureKind::InternalError,
+      AgentError::ConnectionError(_) => FailureKind::ConnectionError,
+      _ => FailureKind::ProviderError,
+    };
+    let message = match e {
+      AgentError::DataError(msg)
+      | AgentError::InternalError(msg)
+      | AgentError::ProviderError(_, msg)
+      | AgentError::AuthError(_, msg)
+      | AgentError::NotFound(_, msg)
+      | AgentError::Duplicate(_, msg)
+      | AgentError::InvalidTaxNumber(_, msg)
+      | AgentError::ConnectionError(msg) => msg.to_string(),
+    };
+    let error_code = e.error_code();
+    Failure {
+      kind,
+      message,
+      error_code,
+      created_at: Utc::now(),
+    }
+  }
+}
+
+impl Failure {
+  /// Failure of saving the PDF of an issued invoice
+  pub fn pdf_error(e: &crate::file::FileError) -> Self {
+    Failure {
+      kind: FailureKind::PdfError,
+      message: e.to_string(),
+      error_code: None,
+      created_at: Utc::now(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StatusChange {
+  pub status: InvoiceStatus,
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum InvoiceError {
+  WrongStatusTransition(InvoiceStatus, InvoiceStatus),
+  // Invoice cannot be corrected
+  NotCorrectable,
+  // Correction item is not on the original invoice,
+  // or its quantity is not negative
+  WrongCorrectionItem(String),
+  // Corrections exceed the original quantity of the item
+  CorrectionExceedsOriginal(String),
+  // Deposit invoice cannot be deducted
+  WrongDeposit(Uuid),
+  // Totals do not match the items
+  WrongTotal,
+  // Deposits exceed the total of the final invoice
+  DepositsExceedTotal,
+  // Payment cannot be registered for the invoice
+  NotPayable,
+  // Payment amount is not positive, or exceeds the outstanding amount
+  WrongPaymentAmount(i32),
+}
+
+impl std::fmt::Display for InvoiceError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      InvoiceError::WrongStatusTransition(from, to) => {
+        write!(f, "A számla állapota nem módosítható! {} -> {}", from, to)
+      }
+      InvoiceError::NotCorrectable => write!(f, "A számla nem helyesbíthető!"),
+      InvoiceError::WrongCorrectionItem(name) => {
+        write!(f, "Hibás helyesbítő tétel! {}", name)
+      }
+      InvoiceError::CorrectionExceedsOriginal(name) => write!(
+        f,
+        "A helyesbítés meghaladja az eredeti számla mennyiségét! {}",
+        name
+      ),
+      InvoiceError::WrongDeposit(id) => write!(f, "Az előlegszámla nem vonható le! {}", id),
+      InvoiceError::WrongTotal => write!(f, "A számla végösszege nem egyezik a tételekkel!"),
+      InvoiceError::DepositsExceedTotal => {
+        write!(f, "Az előlegek meghaladják a végszámla összegét!")
+      }
+      InvoiceError::NotPayable => write!(f, "A számlához nem rögzíthető kifizetés!"),
+      InvoiceError::WrongPaymentAmount(amount) => {
+        write!(f, "Hibás kifizetési összeg! {}", amount)
+      }
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum InvoiceKind {
+  #[default]
+  Normal,
+  // Storno invoice cancelling its referenced invoice
+  Storno,
+  // Corrective invoice returning items of its referenced invoice
+  Corrective,
+  // Proforma invoice, stored separately from the invoices
+  Proforma,
+  // Deposit invoice of an advance payment
+  Deposit,
+  // Final invoice deducting its deposit invoices
+  Final,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -28,7 +300,36 @@ pub struct Invoice {
   pub id: Uuid,
   pub purchase_id: String,
   pub invoice_id: Option<String>,
-  pub has_error: bool,
+  pub kind: InvoiceKind,
+  // Internal ID of the referenced invoice
+  pub reference_id: Option<Uuid>,
+  // Internal ID and invoice ID of its storno invoice
+  pub storno_id: Option<Uuid>,
+  pub storno_invoice_id: Option<String>,
+  // Final invoice of this proforma or deposit invoice
+  pub final_id: Option<Uuid>,
+  // Deposit invoices deducted by this final invoice
+  pub deposit_ids: Vec<Uuid>,
+  // Full invoice content, kept after it is issued
+  pub customer: Customer,
+  pub header: Header,
+  pub total_net: i32,
+  pub total_vat: i32,
+  pub total_gross: i32,
+  // Outstanding amount; None till it is issued
+  pub outstanding: Option<i32>,
+  // Customer can view and pay the invoice online here
+  pub customer_url: Option<String>,
+  // Payment ledger
+  pub payments: Vec<Payment>,
+  // Invoiced items, to validate corrections against
+  pub items: Vec<Item>,
+  pub status: InvoiceStatus,
+  pub status_history: Vec<StatusChange>,
+  pub failure: Option<Failure>,
+  pub attempt_count: u32,
+  pub last_attempt_at: Option<DateTime<Utc>>,
+  pub next_attempt_at: Option<DateTime<Utc>>,
   pub created_by: u32,
   pub created_at: DateTime<Utc>,
 }
@@ -39,13 +340,235 @@ impl Default for Invoice {
       id: Uuid::default(),
       purchase_id: String::default(),
       invoice_id: None,
-      has_error: false,
+      kind: InvoiceKind::default(),
+      reference_id: None,
+      storno_id: None,
+      storno_invoice_id: None,
+      final_id: None,
+      deposit_ids: Vec::new(),
+      customer: Customer::default(),
+      header: Header::default(),
+      total_net: 0,
+      total_vat: 0,
+      total_gross: 0,
+      outstanding: None,
+      customer_url: None,
+      payments: Vec::new(),
+      items: Vec::new(),
+      status: InvoiceStatus::default(),
+      status_history: Vec::new(),
+      failure: None,
+      attempt_count: 0,
+      last_attempt_at: None,
+      next_attempt_at: None,
       created_by: 0,
       created_at: Utc::now(),
     }
   }
 }
 
+impl Invoice {
+  /// Invoice is valid till it is not failed or cancelled.
+  /// A purchase can have only one valid invoice.
+  pub fn is_valid(&self) -> bool {
+    !matches!(
+      self.status,
+      InvoiceStatus::Failed | InvoiceStatus::Cancelled | InvoiceStatus::Stornoed
+    )
+  }
+  /// Check if a storno invoice can be requested for this invoice
+  pub fn can_storno(&self) -> bool {
+    let kind_ok = match self.kind {
+      InvoiceKind::Normal | InvoiceKind::Final => true,
+      // Deducted deposit cannot be stornoed
+      InvoiceKind::Deposit => self.final_id.is_none(),
+      _ => false,
+    };
+    kind_ok && self.storno_id.is_none() && self.status == InvoiceStatus::Issued
+  }
+  /// Invoice is paid when it has no outstanding amount
+  pub fn is_paid(&self) -> bool {
+    matches!(self.outstanding, Some(outstanding) if outstanding <= 0)
+  }
+  /// Payment due date of the invoice
+  pub fn payment_duedate(&self) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(&self.header.payment_duedate, "%Y-%m-%d").ok()
+  }
+  /// Check if the given payment can be registered
+  pub fn check_payment(&self, payment: &Payment) -> Result<(), InvoiceError> {
+    if !matches!(
+      self.status,
+      InvoiceStatus::Issued | InvoiceStatus::PdfMissing
+    ) || self.kind == InvoiceKind::Proforma
+      || self.is_paid()
+    {
+      return Err(InvoiceError::NotPayable);
+    }
+    if payment.amount <= 0 || payment.amount > self.outstanding.unwrap_or_default() {
+      return Err(InvoiceError::WrongPaymentAmount(payment.amount));
+    }
+    Ok(())
+  }
+  /// Add registered payment to the ledger
+  pub fn add_payment(&mut self, payment: Payment) {
+    self.outstanding = Some(self.outstanding.unwrap_or_default() - payment.amount);
+    self.payments.push(payment);
+  }
+  /// Check if the final invoice can be issued for this proforma
+  pub fn can_finalize(&self) -> bool {
+    self.kind == InvoiceKind::Proforma
+      && self.final_id.is_none()
+      && self.status == InvoiceStatus::Issued
+  }
+  /// Check if the given items can correct this invoice.
+  /// Correction items must have negative quantity and totals, and the unit
+  /// price of their original line. Together with the previous corrections
+  /// they cannot exceed the original quantities, and their value cannot
+  /// exceed the original price of the returned quantity.
+  pub fn validate_correction(
+    &self,
+    items: &[Item],
+    previous_corrections: &[Invoice],
+  ) -> Result<(), InvoiceError> {
+    if self.kind != InvoiceKind::Normal || self.status != InvoiceStatus::Issued {
+      return Err(InvoiceError::NotCorrectable);
+    }
+    // Lines of the same item and unit price
+    let same = |a: &Item, b: &Item| a.name == b.name && a.retail_price_net == b.retail_price_net;
+    // Returned quantity of the given item
+    let returned = |item: &Item, items: &[Item]| -> i32 {
+      items
+        .iter()
+        .filter(|i| same(i, item))
+        .map(|i| -i.quantity)
+        .sum()
+    };
+    for item in items {
+      let originals = self
+        .items
+        .iter()
+        .filter(|i| same(i, item))
+        .collect::<Vec<&Item>>();
+      let negative = item.total_price_net < 0 && item.total_price_gross < 0;
+      let free =
+        item.retail_price_net == 0 && item.total_price_net == 0 && item.total_price_gross == 0;
+      if item.quantity >= 0 || originals.is_empty() || !(negative || free) {
+        return Err(InvoiceError::WrongCorrectionItem(item.name.clone()));
+      }
+      let original: i32 = originals.iter().map(|i| i.quantity).sum();
+      let original_gross: i64 = originals.iter().map(|i| i.total_price_gross as i64).sum();
+      let already_returned: i32 = previous_corrections
+        .iter()
+        .map(|c| returned(item, &c.items))
+        .sum();
+      // Value of the returned quantity by the original prices
+      let quantity = -item.quantity as i64;
+      let exceeds_value = -item.total_price_net as i64 > item.retail_price_net as i64 * quantity
+        || -item.total_price_gross as i64 * original as i64 > original_gross * quantity;
+      if already_returned + returned(item, items) > original || exceeds_value {
+        return Err(InvoiceError::CorrectionExceedsOriginal(item.name.clone()));
+      }
+    }
+    Ok(())
+  }
+  /// Set the new status and log the transition
+  /// Returns error if the transition is not allowed
+  pub fn set_status(&mut self, status: InvoiceStatus) -> Result<(), InvoiceError> {
+    if !self.status.can_transition_to(&status) {
+      return Err(InvoiceError::WrongStatusTransition(
+        self.status.clone(),
+        status,
+      ));
+    }
+    let now = Utc::now();
+    match status {
+      // Each submission is a new attempt
+      InvoiceStatus::Submitting => {
+        self.attempt_count += 1;
+        self.last_attempt_at = Some(now);
+        self.next_attempt_at = None;
+      }
+      // Last failure is no longer relevant
+      InvoiceStatus::Issued | InvoiceStatus::PdfMissing => {
+        self.failure = None;
+        self.next_attempt_at = None;
+      }
+      _ => (),
+    }
+    self.status = status.clone();
+    self.status_history.push(StatusChange {
+      status,
+      created_at: now,
+    });
+    Ok(())
+  }
+  /// Set failed status with its failure reason
+  pub fn set_failed(&mut self, failure: Failure) -> Result<(), InvoiceError> {
+    self.set_status(InvoiceStatus::Failed)?;
+    self.failure = Some(failure);
+    Ok(())
+  }
+  /// Queue a failed invoice again by hand
+  /// It starts a new round of automatic retries
+  pub fn resubmit(&mut self) -> Result<(), InvoiceError> {
+    if self.status != InvoiceStatus::Failed {
+      return Err(InvoiceError::WrongStatusTransition(
+        self.status.clone(),
+        InvoiceStatus::Queued,
+      ));
+    }
+    self.set_status(InvoiceStatus::Queued)?;
+    self.attempt_count = 0;
+    self.next_attempt_at = None;
+    Ok(())
+  }
+  /// Invoice is issued, but its PDF is not saved
+  /// Saving is tried again at next_attempt_at
+  pub fn set_pdf_missing(
+    &mut self,
+    failure: Failure,
+    next_attempt_at: DateTime<Utc>,
+  ) -> Result<(), InvoiceError> {
+    if self.status != InvoiceStatus::PdfMissing {
+      self.set_status(InvoiceStatus::PdfMissing)?;
+    }
+    self.failure = Some(failure);
+    self.next_attempt_at = Some(next_attempt_at);
+    Ok(())
+  }
+  /// Queue invoice again after a transient failure
+  pub fn set_retry(
+    &mut self,
+    failure: Failure,
+    next_attempt_at: DateTime<Utc>,
+  ) -> Result<(), InvoiceError> {
+    self.set_status(InvoiceStatus::Queued)?;
+    self.failure = Some(failure);
+    self.next_attempt_at = Some(next_attempt_at);
+    Ok(())
+  }
+  /// In flight invoice could not be looked up
+  /// It stays in flight, and it is looked up again at next_attempt_at
+  /// Each lookup counts as an attempt
+  pub fn set_unresolved(
+    &mut self,
+    failure: Failure,
+    next_attempt_at: DateTime<Utc>,
+  ) -> Result<(), InvoiceError> {
+    if self.status != InvoiceStatus::Submitting {
+      return Err(InvoiceError::WrongStatusTransition(
+        self.status.clone(),
+        InvoiceStatus::Submitting,
+      ));
+    }
+    self.attempt_count += 1;
+    self.last_attempt_at = Some(Utc::now());
+    self.failure = Some(failure);
+    self.next_attempt_at = Some(next_attempt_at);
+    Ok(())
+  }
+}
+
 impl VecPackMember for Invoice {
   type Out = Uuid;
 
@@ -67,6 +590,15 @@ pub struct InvoiceObject {
   pub total_vat: i32,
   pub created_at: DateTime<Utc>,
   pub created_by: u32,
+  // PDF of the issued invoice
+  // kept only while it cannot be saved
+  pub pdf: Option<Vec<u8>>,
+  pub kind: InvoiceKind,
+  // Internal ID and invoice ID of the referenced invoice
+  pub reference_id: Option<Uuid>,
+  pub reference_invoice_id: Option<String>,
+  // Deposit invoices deducted by this final invoice
+  pub deposit_ids: Vec<Uuid>,
 }
 
 impl InvoiceObject {
@@ -94,8 +626,154 @@ impl InvoiceObject {
       total_vat,
       created_at,
       created_by,
+      pdf: None,
+      kind: InvoiceKind::Normal,
+      reference_id: None,
+      reference_invoice_id: None,
+      deposit_ids: Vec::new(),
     }
   }
+  /// Storno invoice request for the given issued invoice
+  /// Its content is the negated content of the invoice
+  pub fn new_storno(invoice: &Invoice, date: NaiveDate, created_by: u32) -> Self {
+    let items = invoice
+      .items
+      .iter()
+      .map(|i| Item {
+        quantity: -i.quantity,
+        total_price_net: -i.total_price_net,
+        total_price_vat: -i.total_price_vat,
+        total_price_gross: -i.total_price_gross,
+        ..i.clone()
+      })
+      .collect();
+    InvoiceObject {
+      cart_id: invoice.purchase_id.clone(),
+      customer: invoice.customer.clone(),
+      header: Header::new(date, date, date, invoice.header.payment_method.clone()),
+      items,
+      total_net: -invoice.total_net,
+      total_vat: -invoice.total_vat,
+      total_gross: -invoice.total_gross,
+      created_by,
+      kind: InvoiceKind::Storno,
+      reference_id: Some(invoice.id),
+      reference_invoice_id: invoice.invoice_id.clone(),
+      internal_id: Uuid::new_v4(),
+      ..InvoiceObject::default()
+    }
+  }
+  /// Corrective invoice request for the given issued invoice
+  pub fn new_corrective(
+    invoice: &Invoice,
+    customer: Customer,
+    header: Header,
+    items: Vec<Item>,
+    created_by: u32,
+  ) -> Self {
+    let total_net = items.iter().map(|i| i.total_price_net).sum();
+    let total_vat = items.iter().map(|i| i.total_price_vat).sum();
+    let total_gross = items.iter().map(|i| i.total_price_gross).sum();
+    InvoiceObject {
+      kind: InvoiceKind::Corrective,
+      reference_id: Some(invoice.id),
+      reference_invoice_id: invoice.invoice_id.clone(),
+      ..InvoiceObject::new(
+        invoice.purchase_id.clone(),
+        Seller::new(),
+        customer,
+        header,
+        items,
+        total_net,
+        total_gross,
+        total_vat,
+        Utc::now(),
+        created_by,
+      )
+    }
+  }
+  /// Final invoice request of the given proforma
+  /// Issued with the proforma content at the given date
+  pub fn new_final(proforma: &Invoice, date: NaiveDate, created_by: u32) -> Self {
+    let mut header = proforma.header.clone();
+    header.date_created = date.to_string();
+    header.payment_duedate = date.to_string();
+    InvoiceObject {
+      reference_id: Some(proforma.id),
+      reference_invoice_id: proforma.invoice_id.clone(),
+      ..InvoiceObject::new(
+        proforma.purchase_id.clone(),
+        Seller::new(),
+        proforma.customer.clone(),
+        header,
+        proforma.items.clone(),
+        proforma.total_net,
+        proforma.total_gross,
+        proforma.total_vat,
+        Utc::now(),
+        created_by,
+      )
+    }
+  }
+  /// Set as final invoice deducting the given deposit invoices.
+  /// Deposits must be issued for the same purchase, and not yet deducted.
+  /// Deducted deposit items are added as negative items.
+  pub fn deduct_deposits(&mut self, deposits: &[Invoice]) -> Result<(), InvoiceError> {
+    let items_total = |items: &[Item]| -> (i32, i32, i32) {
+      items.iter().fold((0, 0, 0), |(net, vat, gross), i| {
+        (
+          net + i.total_price_net,
+          vat + i.total_price_vat,
+          gross + i.total_price_gross,
+        )
+      })
+    };
+
+    if items_total(&self.items) != (self.total_net, self.total_vat, self.total_gross) {
+      return Err(InvoiceError::WrongTotal);
+    }
+
+    let mut deductions: Vec<Item> = Vec::new();
+    for (index, deposit) in deposits.iter().enumerate() {
+      // Each deposit can be deducted only once
+      if deposits[..index].iter().any(|d| d.id == deposit.id)
+        || deposit.kind != InvoiceKind::Deposit
+        || deposit.status != InvoiceStatus::Issued
+        || deposit.purchase_id != self.cart_id
+        || deposit.final_id.is_some()
+        || deposit.storno_id.is_some()
+      {
+        return Err(InvoiceError::WrongDeposit(deposit.id));
+      }
+      let deposit_invoice_id = deposit.invoice_id.clone().unwrap_or_default();
+      deductions.extend(deposit.items.iter().map(|i| Item {
+        name: format!("{} (előleg: {})", i.name, deposit_invoice_id),
+        quantity: -i.quantity,
+        total_price_net: -i.total_price_net,
+        total_price_vat: -i.total_price_vat,
+        total_price_gross: -i.total_price_gross,
+        ..i.clone()
+      }));
+    }
+
+    let (net, vat, gross) = items_total(&deductions);
+    if self.total_gross + gross < 0 {
+      return Err(InvoiceError::DepositsExceedTotal);
+    }
+
+    self.items.extend(deductions);
+    self.total_net += net;
+    self.total_vat += vat;
+    self.total_gross += gross;
+    self.kind = InvoiceKind::Final;
+    self.deposit_ids = deposits.iter().map(|d| d.id).collect();
+    Ok(())
+  }
+  /// Order number we send to the invoice agent
+  /// to identify the issued invoice later
+  pub fn order_number(&self) -> String {
+    self.internal_id.to_simple().to_string()
+  }
 }
 
 impl Default for InvoiceObject {
@@ -112,6 +790,11 @@ impl Default for InvoiceObject {
       total_vat: 0,
       created_at: Utc::now(),
       created_by: 0,
+      pdf: None,
+      kind: InvoiceKind::default(),
+      reference_id: None,
+      reference_invoice_id: None,
+      deposit_ids: Vec::new(),
     }
   }
 }
@@ -122,7 +805,30 @@ impl From<InvoiceObject> for Invoice {
       id: i.internal_id,
       purchase_id: i.cart_id,
       invoice_id: None,
-      has_error: false,
+      kind: i.kind,
+      reference_id: i.reference_id,
+      storno_id: None,
+      storno_invoice_id: None,
+      final_id: None,
+      deposit_ids: i.deposit_ids,
+      customer: i.customer,
+      header: i.header,
+      total_net: i.total_net,
+      total_vat: i.total_vat,
+      total_gross: i.total_gross,
+      outstanding: None,
+      customer_url: None,
+      payments: Vec::new(),
+      items: i.items,
+      status: InvoiceStatus::Queued,
+      status_history: vec![StatusChange {
+        status: InvoiceStatus::Queued,
+        created_at: i.created_at,
+      }],
+      failure: None,
+      attempt_count: 0,
+      last_attempt_at: None,
+      next_attempt_at: None,
       created_by: i.created_by,
       created_at: i.created_at,
     }
@@ -136,6 +842,125 @@ impl VecPackMember for InvoiceObject {
   }
 }
 
+/// Invoice layout of the first release
+/// Stored ones are upgraded when the store is loaded
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InvoiceV0 {
+  pub id: Uuid,
+  pub purchase_id: String,
+  pub invoice_id: Option<String>,
+  pub has_error: bool,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl Default for InvoiceV0 {
+  fn default() -> Self {
+    InvoiceV0 {
+      id: Uuid::default(),
+      purchase_id: String::default(),
+      invoice_id: None,
+      has_error: false,
+      created_by: 0,
+      created_at: Utc::now(),
+    }
+  }
+}
+
+impl packman::TryFrom for Invoice {
+  type TryFrom = InvoiceV0;
+}
+
+impl From<InvoiceV0> for Invoice {
+  fn from(i: InvoiceV0) -> Self {
+    let status = match (&i.invoice_id, i.has_error) {
+      (Some(_), _) => InvoiceStatus::Issued,
+      (None, true) => InvoiceStatus::Failed,
+      (None, false) => InvoiceStatus::Queued,
+    };
+    Invoice {
+      id: i.id,
+      purchase_id: i.purchase_id,
+      invoice_id: i.invoice_id,
+      status: status.clone(),
+      status_history: vec![StatusChange {
+        status: status.clone(),
+        created_at: i.created_at,
+      }],
+      failure: match status {
+        InvoiceStatus::Failed => Some(Failure {
+          kind: FailureKind::ProviderError,
+          message: "A számla kiállítása sikertelen volt".to_string(),
+          error_code: None,
+          created_at: i.created_at,
+        }),
+        _ => None,
+      },
+      created_by: i.created_by,
+      created_at: i.created_at,
+      ..Invoice::default()
+    }
+  }
+}
+
+/// Invoice object layout of the first release
+/// Stored ones are upgraded when the store is loaded
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InvoiceObjectV0 {
+  pub internal_id: Uuid,
+  pub cart_id: String,
+  pub seller: Seller,
+  pub customer: Customer,
+  pub header: Header,
+  pub items: Vec<Item>,
+  pub total_net: i32,
+  pub total_gross: i32,
+  pub total_vat: i32,
+  pub created_at: DateTime<Utc>,
+  pub created_by: u32,
+}
+
+impl Default for InvoiceObjectV0 {
+  fn default() -> Self {
+    InvoiceObjectV0 {
+      internal_id: Uuid::default(),
+      cart_id: String::default(),
+      seller: Seller::default(),
+      customer: Customer::default(),
+      header: Header::default(),
+      items: Vec::new(),
+      total_net: 0,
+      total_gross: 0,
+      total_vat: 0,
+      created_at: Utc::now(),
+      created_by: 0,
+    }
+  }
+}
+
+impl packman::TryFrom for InvoiceObject {
+  type TryFrom = InvoiceObjectV0;
+}
+
+impl From<InvoiceObjectV0> for InvoiceObject {
+  fn from(i: InvoiceObjectV0) -> Self {
+    InvoiceObject {
+      internal_id: i.internal_id,
+      cart_id: i.cart_id,
+      seller: i.seller,
+      customer: i.customer,
+      header: i.header,
+      items: i.items,
+      total_net: i.total_net,
+      total_gross: i.total_gross,
+      total_vat: i.total_vat,
+      created_at: i.created_at,
+      created_by: i.created_by,
+      ..InvoiceObject::default()
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Seller {}
 
@@ -172,19 +997,14 @@ impl Customer {
   }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub enum PaymentMethod {
+  #[default]
   Cash,
   Transfer,
   Card,
 }
 
-impl Default for PaymentMethod {
-  fn default() -> Self {
-    PaymentMethod::Cash
-  }
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Header {
   pub date_created: String,
@@ -204,7 +1024,7 @@ impl Header {
       date_created: date_created.to_string(),
       date_completion: date_completion.to_string(),
       payment_duedate: payment_duedate.to_string(),
-      payment_method: payment_method,
+      payment_method,
     }
   }
 }
@@ -221,17 +1041,19 @@ pub struct Item {
   pub total_price_gross: i32,
 }
 
+// Used by the currently disabled item price validation
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum ItemError {
   TotalUnitNetError,
   TotalUnitGrossError,
 }
 
-impl ToString for ItemError {
-  fn to_string(&self) -> String {
+impl std::fmt::Display for ItemError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
-      ItemError::TotalUnitNetError => "Nem megfelelő az adott tétel totál nettó ára!".into(),
-      ItemError::TotalUnitGrossError => "Nem megfelelő az adott tétel totál bruttó ára!".into(),
+      ItemError::TotalUnitNetError => write!(f, "Nem megfelelő az adott tétel totál nettó ára!"),
+      ItemError::TotalUnitGrossError => write!(f, "Nem megfelelő az adott tétel totál bruttó ára!"),
     }
   }
 }
@@ -269,22 +1091,17 @@ impl Item {
   }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub enum VAT {
   AAM,
   FAD,
   TAM,
   _5,
   _18,
+  #[default]
   _27,
 }
 
-impl Default for VAT {
-  fn default() -> Self {
-    VAT::_27
-  }
-}
-
 impl VAT {
   pub fn from_str(str: &str) -> Result<VAT, String> {
     match str {
@@ -302,6 +1119,19 @@ impl VAT {
   }
 }
 
+impl std::fmt::Display for VAT {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      VAT::AAM => write!(f, "AAM"),
+      VAT::FAD => write!(f, "FAD"),
+      VAT::TAM => write!(f, "TAM"),
+      VAT::_5 => write!(f, "5"),
+      VAT::_18 => write!(f, "18"),
+      VAT::_27 => write!(f, "27"),
+    }
+  }
+}
+
 impl Mul<VAT> for i32 {
   type Output = i32;
 
@@ -322,6 +1152,138 @@ impl Mul<VAT> for i32 {
 mod tests {
   use super::*;
   #[test]
+  fn test_status_transition() {
+    let mut invoice: Invoice = InvoiceObject::default().into();
+    assert_eq!(invoice.status, InvoiceStatus::Queued);
+    assert!(invoice.set_status(InvoiceStatus::Issued).is_err());
+    assert!(invoice.set_status(InvoiceStatus::Submitting).is_ok());
+    assert!(invoice
+      .set_failed((&AgentError::ProviderError(57, "Hibás adószám".into())).into())
+      .is_ok());
+    assert!(!invoice.is_valid());
+    assert_eq!(invoice.failure.as_ref().unwrap().error_code, Some(57));
+    assert!(invoice.set_status(InvoiceStatus::Queued).is_ok());
+    assert!(invoice.set_status(InvoiceStatus::Submitting).is_ok());
+    assert!(invoice.set_status(InvoiceStatus::Issued).is_ok());
+    assert!(invoice.is_valid());
+    assert!(invoice.failure.is_none());
+    assert_eq!(invoice.attempt_count, 2);
+    assert_eq!(invoice.status_history.len(), 6);
+  }
+  #[test]
+  fn test_validate_correction() {
+    let item = |quantity: i32, price: i32, total_net: i32| Item {
+      name: "Zsák".into(),
+      quantity,
+      retail_price_net: price,
+      total_price_net: total_net,
+      total_price_vat: total_net * 27 / 100,
+      total_price_gross: total_net * 127 / 100,
+      ..Item::default()
+    };
+    let mut invoice: Invoice = InvoiceObject {
+      items: vec![item(3, 100, 300)],
+      ..InvoiceObject::default()
+    }
+    .into();
+    assert!(invoice
+      .validate_correction(&[item(-1, 100, -100)], &[])
+      .is_err());
+    invoice.status = InvoiceStatus::Issued;
+    let previous: Invoice = InvoiceObject {
+      items: vec![item(-2, 100, -200)],
+      ..InvoiceObject::default()
+    }
+    .into();
+    assert!(invoice
+      .validate_correction(&[item(-1, 100, -100)], &[])
+      .is_ok());
+    assert!(invoice
+      .validate_correction(&[item(1, 100, 100)], &[])
+      .is_err());
+    assert!(invoice
+      .validate_correction(&[item(-1, 100, -100)], std::slice::from_ref(&previous))
+      .is_ok());
+    assert!(invoice
+      .validate_correction(&[item(-2, 100, -200)], &[previous])
+      .is_err());
+    // Totals must be negative
+    assert!(invoice
+      .validate_correction(&[item(-1, 100, 100)], &[])
+      .is_err());
+    // Value cannot exceed the original price of the returned quantity
+    assert!(invoice
+      .validate_correction(&[item(-1, 100, -150)], &[])
+      .is_err());
+    // Unit price must match the original line
+    assert!(invoice
+      .validate_correction(&[item(-1, 150, -150)], &[])
+      .is_err());
+  }
+  #[test]
+  fn test_new_storno() {
+    let invoice: Invoice = crate::mock::invoice_object().into();
+    let date = NaiveDate::from_ymd_opt(2021, 3, 10).unwrap();
+    let storno: Invoice = InvoiceObject::new_storno(&invoice, date, 1).into();
+    assert_eq!(storno.customer.name, "Kert Kft.");
+    assert_eq!(storno.header.payment_method, invoice.header.payment_method);
+    assert_eq!(
+      (storno.total_net, storno.total_vat, storno.total_gross),
+      (-1000, -270, -1270)
+    );
+    let item = &storno.items[0];
+    assert_eq!(
+      (item.quantity, item.retail_price_net, item.total_price_gross),
+      (-1, 1000, -1270)
+    );
+  }
+
+  #[test]
+  fn test_deduct_deposits() {
+    let item = |total: i32| Item {
+      name: "Kerítés".into(),
+      quantity: 1,
+      retail_price_net: total,
+      total_price_net: total,
+      total_price_gross: total,
+      ..Item::default()
+    };
+    let mut deposit: Invoice = InvoiceObject {
+      kind: InvoiceKind::Deposit,
+      items: vec![item(400)],
+      ..InvoiceObject::default()
+    }
+    .into();
+    deposit.status = InvoiceStatus::Issued;
+    let final_object = InvoiceObject {
+      items: vec![item(1000)],
+      total_net: 1000,
+      total_gross: 1000,
+      ..InvoiceObject::default()
+    };
+
+    let mut o = final_object.clone();
+    assert!(o.deduct_deposits(std::slice::from_ref(&deposit)).is_ok());
+    assert_eq!(o.kind, InvoiceKind::Final);
+    assert_eq!(o.items.len(), 2);
+    assert_eq!((o.total_net, o.total_gross), (600, 600));
+
+    let mut o = final_object.clone();
+    assert!(o
+      .deduct_deposits(&[deposit.clone(), deposit.clone(), deposit.clone()])
+      .is_err());
+
+    let mut o = final_object.clone();
+    assert!(matches!(
+      o.deduct_deposits(&[deposit.clone(), deposit.clone()]),
+      Err(InvoiceError::WrongDeposit(id)) if id == deposit.id
+    ));
+
+    deposit.final_id = Some(Uuid::new_v4());
+    let mut o = final_object;
+    assert!(o.deduct_deposits(&[deposit]).is_err());
+  }
+  #[test]
   fn test_vat_multiply() {
     use VAT::*;
     assert_eq!(100, 100 * AAM);
@@ -333,4 +1295,56 @@ mod tests {
     assert_eq!(1415, 1114 * _27);
     assert_eq!((1114 * _27) * 9, 1114 * _27 * 9);
   }
+
+  impl VecPackMember for InvoiceV0 {
+    type Out = Uuid;
+    fn get_id(&self) -> &Self::Out {
+      &self.id
+    }
+  }
+
+  #[test]
+  fn test_upgrade_v0() {
+    use packman::VecPack;
+    let path = std::env::temp_dir().join(format!("invoices_v0_{}", Uuid::new_v4()));
+    let (issued, failed, queued) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    {
+      // Store of the first release
+      let mut store: VecPack<InvoiceV0> = VecPack::load_or_init(path.clone()).unwrap();
+      for (id, invoice_id, has_error) in [
+        (issued, Some("GZ-2021-1".to_string()), false),
+        (failed, None, true),
+        (queued, None, false),
+      ] {
+        store
+          .insert(InvoiceV0 {
+            id,
+            purchase_id: "p1".into(),
+            invoice_id,
+            has_error,
+            ..InvoiceV0::default()
+          })
+          .unwrap();
+      }
+    }
+
+    let store: VecPack<Invoice> = VecPack::try_load_or_init(path.clone()).unwrap();
+    let status = |id| store.find_id(&id).unwrap().unpack().status.clone();
+    assert_eq!(status(issued), InvoiceStatus::Issued);
+    assert_eq!(status(failed), InvoiceStatus::Failed);
+    assert_eq!(status(queued), InvoiceStatus::Queued);
+    assert_eq!(
+      store
+        .find_id(&issued)
+        .unwrap()
+        .unpack()
+        .invoice_id
+        .as_deref(),
+      Some("GZ-2021-1")
+    );
+
+    // Upgraded invoices are saved in the new layout
+    let store: VecPack<Invoice> = VecPack::load_or_init(path).unwrap();
+    assert_eq!(store.len(), 3);
+  }
 }
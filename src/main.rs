@@ -1,223 +1,373 @@
+#![allow(
+  clippy::too_many_arguments,
+  clippy::upper_case_acronyms,
+  clippy::new_ret_no_self,
+  clippy::enum_variant_names
+)]
+
 extern crate base64;
 extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
 use chrono::{DateTime, NaiveDate, Utc};
-use gzlib::proto::invoice::{
+use invoice::{InvoiceKind, InvoiceStatus, PaymentMethod};
+use packman::*;
+use prelude::*;
+use proto::invoice::{
   invoice_form::{self, PaymentKind},
   *,
 };
-use invoice::PaymentMethod;
-use packman::*;
-use prelude::*;
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{env, error::Error};
-use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex, Notify};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport::Server, Request, Response, Status};
 use uuid::Uuid;
 
+mod cli;
 mod file;
+mod index;
 mod invoice;
+mod mock;
 mod prelude;
+mod processor;
+mod proto;
+mod rate_limit;
+mod receipt;
+mod report;
+mod retry;
 mod szamlazzhu;
+mod webhook;
 
 // How many worker can work together
-// const WORKER_MAX: u32 = 2;
+// if INVOICE_WORKER_COUNT ENV is not set
+const WORKER_MAX: usize = 2;
 
-const PDF_FOLDER_NAME: &'static str = "pdf";
+// How often the processor checks the invoice object store
+// if INVOICE_POLL_INTERVAL_SECS ENV is not set
+const POLL_INTERVAL_SECS: u64 = 5;
 
-struct InvoiceProcessor<T>
-where
-  T: invoice::InvoiceAgent + Send,
-{
-  agent: Arc<Mutex<T>>,
-}
+// Wait before restarting a stopped invoice processor
+const PROCESSOR_RESTART_DELAY_SECS: u64 = 5;
 
-impl<T> InvoiceProcessor<T>
-where
-  T: invoice::InvoiceAgent + Send,
-{
-  fn new(agent: T) -> Self
-  where
-    T: invoice::InvoiceAgent + Send,
-  {
-    InvoiceProcessor {
-      agent: Arc::new(Mutex::new(agent)),
-    }
-  }
-  async fn start(
-    &mut self,
-    mut new_invoice_chan_rx: mpsc::Receiver<invoice::InvoiceObject>,
-    invoice_objects: Arc<Mutex<VecPack<invoice::InvoiceObject>>>,
-    invoices: Arc<Mutex<VecPack<invoice::Invoice>>>,
-  ) {
-    // Do the processes
-    // Infinite loop till the sender is alive
-    while let Some(invoice_object) = new_invoice_chan_rx.recv().await {
-      let inner_id = invoice_object.internal_id;
-
-      match self.agent.lock().await.create_invoice(invoice_object).await {
-        Ok(invoice_summary) => {
-          // Then try to save it as a PDF file
-          match file::base64_decode(&invoice_summary.pdf_base64.replace("\n", "")) {
-            Ok(bytes) => file::save_file(
-              bytes,
-              std::path::PathBuf::from(format!(
-                "data/{}/{}.pdf",
-                PDF_FOLDER_NAME, invoice_summary.invoice_id
-              )),
-            )
-            .await
-            .expect(&format!(
-              "Invoice PDF SAVE ERROR: {}",
-              invoice_summary.invoice_id
-            )),
-            Err(_) => error!(
-              "Invoice PDF BASE64 DECODE error: {}",
-              invoice_summary.invoice_id
-            ),
-          }
+// Sending a receipt at most this many times,
+// while its requests are lost
+const RECEIPT_ATTEMPTS: u32 = 3;
 
-          // Set InvoiceID
-          invoices
-            .lock()
-            .await
-            .into_iter()
-            .filter(|i| i.unpack().get_id() == &inner_id)
-            .for_each(|i| {
-              i.as_mut().unpack().invoice_id = Some(invoice_summary.invoice_id.clone())
-            });
-
-          // And remove InvoiceObject
-          invoice_objects
-            .lock()
-            .await
-            .remove_pack(&inner_id)
-            .expect("Error while removing invoice object from storage");
-        }
-        Err(_) => {
-          {
-            // Set error occured
-            invoices
-              .lock()
-              .await
-              .into_iter()
-              .filter(|i| i.unpack().get_id() == &inner_id)
-              .for_each(|i| {
-                i.as_mut().unpack().has_error = true;
-              });
-
-            // And remove InvoiceObject
-            invoice_objects
-              .lock()
-              .await
-              .remove_pack(&inner_id)
-              .expect("Error while removing invoice object from storage");
-          }
-        }
-      }
-    }
+// Status changes kept for the slow subscribers
+const STATUS_CHANNEL_SIZE: usize = 100;
 
-    error!("Background process closed!");
-  }
-}
+const PDF_FOLDER_NAME: &str = "pdf";
 
 struct InvoiceService {
-  send_channel: Mutex<mpsc::Sender<invoice::InvoiceObject>>,
+  // Notify the processor about new invoice objects
+  processor_notify: Arc<Notify>,
+  // Set by the processor supervisor
+  processor_running: Arc<AtomicBool>,
   invoice_store: Arc<Mutex<VecPack<invoice::Invoice>>>,
   invoice_object_store: Arc<Mutex<VecPack<invoice::InvoiceObject>>>,
+  proforma_store: Arc<Mutex<VecPack<invoice::Invoice>>>,
+  receipt_store: Arc<Mutex<VecPack<receipt::Receipt>>>,
+  receipt_agent: Arc<dyn receipt::ReceiptAgent + Send + Sync>,
+  // Synchronous invoice agent requests
+  // Invoices are issued by the processor
+  invoice_agent: Arc<dyn invoice::InvoiceAgent + Send + Sync>,
+  // Shared with the invoice processor
+  rate_limiter: Arc<rate_limit::RateLimiter>,
+  // Secondary indices of the invoice store
+  // Refreshed before each search
+  invoice_index: Mutex<index::InvoiceIndex>,
+  // Secondary indices of the proforma store
+  proforma_index: Mutex<index::InvoiceIndex>,
+  // Invoices with a payment under registration
+  // Reserved under the invoice store lock
+  payment_reservations: Mutex<HashSet<Uuid>>,
+  // Status changes of the invoices
+  // Shared with the invoice processor
+  status_tx: processor::StatusSender,
+  // Shared with the invoice processor and the webhook notifier
+  webhook_log: Arc<webhook::DeliveryLog>,
+  // Folder of the invoice and receipt PDFs
+  pdf_folder: PathBuf,
 }
 
 impl InvoiceService {
   fn new(
-    sender: mpsc::Sender<invoice::InvoiceObject>,
+    processor_notify: Arc<Notify>,
+    processor_running: Arc<AtomicBool>,
     invoices: Arc<Mutex<VecPack<invoice::Invoice>>>,
     invoice_objects: Arc<Mutex<VecPack<invoice::InvoiceObject>>>,
+    proformas: Arc<Mutex<VecPack<invoice::Invoice>>>,
+    receipts: Arc<Mutex<VecPack<receipt::Receipt>>>,
+    receipt_agent: Arc<dyn receipt::ReceiptAgent + Send + Sync>,
+    invoice_agent: Arc<dyn invoice::InvoiceAgent + Send + Sync>,
+    rate_limiter: Arc<rate_limit::RateLimiter>,
+    status_tx: processor::StatusSender,
+    webhook_log: Arc<webhook::DeliveryLog>,
+    pdf_folder: PathBuf,
   ) -> Self {
     Self {
-      send_channel: Mutex::new(sender),
+      processor_notify,
+      processor_running,
       invoice_store: invoices,
       invoice_object_store: invoice_objects,
+      proforma_store: proformas,
+      receipt_store: receipts,
+      receipt_agent,
+      invoice_agent,
+      rate_limiter,
+      invoice_index: Mutex::new(index::InvoiceIndex::new()),
+      proforma_index: Mutex::new(index::InvoiceIndex::new()),
+      payment_reservations: Mutex::new(HashSet::new()),
+      status_tx,
+      webhook_log,
+      pdf_folder,
     }
   }
 
+  /// Set the invoice failed whose invoice object cannot be saved,
+  /// so it can be created again
+  async fn set_enqueue_failed(
+    &self,
+    store: &Mutex<VecPack<invoice::Invoice>>,
+    id: &Uuid,
+  ) -> ServiceResult<()> {
+    let (previous, invoice) = {
+      let mut store = store.lock().await;
+      let mut i = store.find_id_mut(id)?.as_mut();
+      let invoice = i.unpack();
+      let previous = invoice.status.clone();
+      invoice.set_status(InvoiceStatus::Failed)?;
+      (previous, invoice.clone())
+    };
+    self.publish_status(&previous, invoice).await;
+    Ok(())
+  }
+
+  /// Record the webhook deliveries of the status change
+  /// from the given previous status, and publish it to the subscribers
+  async fn publish_status(&self, previous: &InvoiceStatus, invoice: invoice::Invoice) {
+    self.webhook_log.record(previous, &invoice).await;
+    // Error only if there is no subscriber
+    let _ = self.status_tx.send(invoice);
+  }
+
   async fn create_new(&self, r: InvoiceForm) -> ServiceResult<InvoiceData> {
-    // Create seller
-    let seller = invoice::Seller::new();
+    let invoice_object = invoice_object_from_form(r)?;
 
-    // Create customer
-    let c = match r.customer {
-      Some(_customer) => _customer,
-      None => return Err(ServiceError::internal_error("Missing customer object")),
-    };
-    let customer = invoice::Customer::new(c.name, c.tax_number, c.zip, c.location, c.street);
-
-    // Date parser helper
-    // DateTime RFC3339 to NaiveDate
-    let date_parser = |datestr: &str| -> ServiceResult<NaiveDate> {
-      let date = DateTime::parse_from_rfc3339(datestr)
-        .map_err(|_| ServiceError::internal_error("A megadott dátum hibás"))?
-        .with_timezone(&Utc);
-      Ok(date.naive_utc().date())
+    let i: invoice::Invoice = invoice_object.clone().into();
+
+    {
+      let mut invoice_store = self.invoice_store.lock().await;
+
+      // Check if there is any valid invoice for the given purchase ID
+      // If yes, then return Err(Already exist)
+      if has_valid_invoice(&invoice_store, &self.invoice_index, &i.purchase_id).await {
+        return Err(ServiceError::already_exist(
+          "A megadott vásárláshoz már tartozik érvényes számla!",
+        ));
+      }
+
+      // Store invoice
+      invoice_store
+        .insert(i.clone())
+        .map_err(|_| ServiceError::internal_error("Error while saving invoice to invoice store"))?;
+    }
+
+    // Save invoice object for the processor.
+    // If it fails, then we mark the invoice as failed,
+    // so it can be created again.
+    if let Err(e) = self.enqueue(invoice_object).await {
+      self.set_enqueue_failed(&self.invoice_store, &i.id).await?;
+      return Err(e);
+    }
+
+    Ok(i.into())
+  }
+
+  async fn create_proforma(&self, r: InvoiceForm) -> ServiceResult<InvoiceData> {
+    let invoice_object = invoice::InvoiceObject {
+      kind: InvoiceKind::Proforma,
+      ..invoice_object_from_form(r)?
     };
 
-    let payment_kind: PaymentKind = PaymentKind::from_i32(r.payment_kind)
-      .ok_or(ServiceError::internal_error("Wrong paymentkind ENUM!"))?;
+    let i: invoice::Invoice = invoice_object.clone().into();
+
+    // Store proforma
+    self
+      .proforma_store
+      .lock()
+      .await
+      .insert(i.clone())
+      .map_err(|_| ServiceError::internal_error("Error while saving proforma to proforma store"))?;
 
-    println!("payment kind is {:?} - {}", &payment_kind, &r.payment_kind);
+    if let Err(e) = self.enqueue(invoice_object).await {
+      self.set_enqueue_failed(&self.proforma_store, &i.id).await?;
+      return Err(e);
+    }
 
-    let header = invoice::Header::new(
-      date_parser(&r.date)?,
-      date_parser(&r.completion_date)?,
-      date_parser(&r.payment_duedate)?,
-      match payment_kind {
-        PaymentKind::Cash => PaymentMethod::Cash,
-        PaymentKind::Card => PaymentMethod::Card,
-        PaymentKind::Transfer => PaymentMethod::Transfer,
-      },
-    );
+    Ok(i.into())
+  }
 
-    let map_item = |i: &invoice_form::Item| -> ServiceResult<invoice::Item> {
-      invoice::Item::new(
-        i.name.to_string(),
-        i.quantity,
-        i.unit.to_string(),
-        i.price_unit_net,
-        invoice::VAT::from_str(&i.vat).map_err(|e| ServiceError::bad_request(&e))?,
-        i.total_price_net,
-        i.total_price_vat,
-        i.total_price_gross,
-      )
-      .map_err(|_| {
-        ServiceError::bad_request(&format!(
-          "A megadott tétel ár adatai (nettó, áfa, bruttó) nem helyesek! {:?}",
-          i
-        ))
-      })
+  async fn get_proforma_by_id(&self, r: ByIdRequest) -> ServiceResult<InvoiceData> {
+    let id = Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
+    let res = self
+      .proforma_store
+      .lock()
+      .await
+      .find_id(&id)?
+      .unpack()
+      .clone();
+
+    Ok(res.into())
+  }
+
+  async fn finalize_proforma(&self, r: FinalizeProformaRequest) -> ServiceResult<InvoiceData> {
+    let id = Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
+
+    let proforma = self
+      .proforma_store
+      .lock()
+      .await
+      .find_id(&id)?
+      .unpack()
+      .clone();
+
+    if !proforma.can_finalize() {
+      return Err(ServiceError::bad_request(
+        "A díjbekérő ebben az állapotban nem véglegesíthető!",
+      ));
+    }
+
+    // Final invoice is created from the proforma content
+    let invoice_object =
+      invoice::InvoiceObject::new_final(&proforma, parse_date(&r.date)?, r.created_by);
+
+    let i: invoice::Invoice = invoice_object.clone().into();
+
+    {
+      let mut invoice_store = self.invoice_store.lock().await;
+
+      if has_valid_invoice(&invoice_store, &self.invoice_index, &i.purchase_id).await {
+        return Err(ServiceError::already_exist(
+          "A megadott vásárláshoz már tartozik érvényes számla!",
+        ));
+      }
+
+      invoice_store
+        .insert(i.clone())
+        .map_err(|_| ServiceError::internal_error("Error while saving invoice to invoice store"))?;
+    }
+
+    // Link the final invoice to its proforma
+    self
+      .proforma_store
+      .lock()
+      .await
+      .find_id_mut(&id)?
+      .as_mut()
+      .unpack()
+      .final_id = Some(i.id);
+
+    if let Err(e) = self.enqueue(invoice_object).await {
+      self.set_enqueue_failed(&self.invoice_store, &i.id).await?;
+      return Err(e);
+    }
+
+    Ok(i.into())
+  }
+
+  async fn create_deposit(&self, r: InvoiceForm) -> ServiceResult<InvoiceData> {
+    let invoice_object = invoice::InvoiceObject {
+      kind: InvoiceKind::Deposit,
+      ..invoice_object_from_form(r)?
     };
 
-    let items = r
-      .items
+    let i: invoice::Invoice = invoice_object.clone().into();
+
+    // Purchase can have more deposit invoices,
+    // but not after its final invoice
+    {
+      let mut invoice_store = self.invoice_store.lock().await;
+
+      if has_valid_invoice(&invoice_store, &self.invoice_index, &i.purchase_id).await {
+        return Err(ServiceError::already_exist(
+          "A megadott vásárláshoz már tartozik érvényes számla!",
+        ));
+      }
+
+      invoice_store
+        .insert(i.clone())
+        .map_err(|_| ServiceError::internal_error("Error while saving invoice to invoice store"))?;
+    }
+
+    if let Err(e) = self.enqueue(invoice_object).await {
+      self.set_enqueue_failed(&self.invoice_store, &i.id).await?;
+      return Err(e);
+    }
+
+    Ok(i.into())
+  }
+
+  async fn create_final(&self, r: FinalInvoiceForm) -> ServiceResult<InvoiceData> {
+    let form = r
+      .invoice
+      .ok_or_else(|| ServiceError::bad_request("Missing invoice object"))?;
+    let mut invoice_object = invoice_object_from_form(form)?;
+
+    let deposit_ids = r
+      .deposit_ids
       .iter()
-      .map(map_item)
-      .collect::<Result<Vec<invoice::Item>, ServiceError>>()?;
+      .map(|id| Uuid::parse_str(id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID")))
+      .collect::<ServiceResult<Vec<Uuid>>>()?;
 
-    // Create Invoice Object
-    let invoice_object = invoice::InvoiceObject::new(
-      r.purchase_id,
-      seller,
-      customer,
-      header,
-      items,
-      r.total_net,
-      r.total_gross,
-      r.total_vat,
-      chrono::Utc::now(),
-      r.created_by,
-    );
+    let i: invoice::Invoice = {
+      let mut invoice_store = self.invoice_store.lock().await;
+
+      if has_valid_invoice(&invoice_store, &self.invoice_index, &invoice_object.cart_id).await {
+        return Err(ServiceError::already_exist(
+          "A megadott vásárláshoz már tartozik érvényes számla!",
+        ));
+      }
+
+      let deposits = deposit_ids
+        .iter()
+        .map(|id| Ok(invoice_store.find_id(id)?.unpack().clone()))
+        .collect::<ServiceResult<Vec<invoice::Invoice>>>()?;
+
+      invoice_object.deduct_deposits(&deposits)?;
+
+      let i: invoice::Invoice = invoice_object.clone().into();
+      invoice_store
+        .insert(i.clone())
+        .map_err(|_| ServiceError::internal_error("Error while saving invoice to invoice store"))?;
+
+      // Link the deposits to their final invoice
+      for deposit_id in &deposit_ids {
+        invoice_store
+          .find_id_mut(deposit_id)?
+          .as_mut()
+          .unpack()
+          .final_id = Some(i.id);
+      }
+
+      i
+    };
+
+    if let Err(e) = self.enqueue(invoice_object).await {
+      self.set_enqueue_failed(&self.invoice_store, &i.id).await?;
+      return Err(e);
+    }
+
+    Ok(i.into())
+  }
 
+  async fn enqueue(&self, invoice_object: invoice::InvoiceObject) -> ServiceResult<()> {
     // Save invoice object to invoice_object_store
     self
       .invoice_object_store
@@ -231,31 +381,14 @@ impl InvoiceService {
         ))
       })?;
 
-    // Send InvoiceObject to create
-    self
-      .send_channel
-      .lock()
-      .await
-      .send(invoice_object.clone())
-      .await
-      .map_err(|e| {
-        ServiceError::internal_error(&format!(
-          "Error while sending invoice_object via send_channel; {:?}",
-          e
-        ))
-      })?;
-
-    let i: invoice::Invoice = invoice_object.into();
-
-    // Store invoice
+    // Notify processor to create it
+    self.processor_notify.notify_one();
+    // New invoices start as queued
     self
-      .invoice_store
-      .lock()
-      .await
-      .insert(i.clone())
-      .map_err(|_| ServiceError::internal_error("Error while saving invoice to invoice store"))?;
+      .publish_status(&InvoiceStatus::Queued, invoice_object.into())
+      .await;
 
-    Ok(i.into())
+    Ok(())
   }
 
   async fn get_by_id(&self, r: ByIdRequest) -> ServiceResult<InvoiceData> {
@@ -271,119 +404,1897 @@ impl InvoiceService {
     Ok(res.into())
   }
 
-  async fn download(&self, r: DownloadRequest) -> ServiceResult<DownloadResponse> {
-    let pdf_base64 = file::load_invoice_base64(&r.invoice_id)
-      .await
-      .map_err(|e| ServiceError::internal_error(&e.to_string()))?;
+  async fn get_invoice(&self, r: ByIdRequest) -> ServiceResult<InvoiceDetails> {
+    let id = Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
 
-    Ok(DownloadResponse { pdf_base64 })
-  }
-}
+    // Proformas are stored separately
+    let res = match self.invoice_store.lock().await.find_id(&id) {
+      Ok(i) => i.unpack().clone(),
+      Err(_) => self
+        .proforma_store
+        .lock()
+        .await
+        .find_id(&id)?
+        .unpack()
+        .clone(),
+    };
 
-#[tonic::async_trait]
-impl invoice_server::Invoice for InvoiceService {
-  async fn create_new(
-    &self,
-    request: Request<InvoiceForm>,
-  ) -> Result<Response<InvoiceData>, Status> {
-    let res = self.create_new(request.into_inner()).await?;
-    Ok(Response::new(res))
+    Ok(res.into())
   }
 
-  async fn get_by_id(
-    &self,
-    request: Request<ByIdRequest>,
-  ) -> Result<Response<InvoiceData>, Status> {
-    let res = self.get_by_id(request.into_inner()).await?;
-    Ok(Response::new(res))
-  }
+  async fn retry_invoice(&self, r: RetryRequest) -> ServiceResult<InvoiceData> {
+    let id = Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
 
-  async fn download(
-    &self,
-    request: Request<DownloadRequest>,
-  ) -> Result<Response<DownloadResponse>, Status> {
-    let res = self.download(request.into_inner()).await?;
-    Ok(Response::new(res))
-  }
-}
+    // Failed proformas can be retried as well
+    let (store, store_index) = if self.proforma_store.lock().await.find_id(&id).is_ok() {
+      (&self.proforma_store, &self.proforma_index)
+    } else {
+      (&self.invoice_store, &self.invoice_index)
+    };
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-  pretty_env_logger::init();
+    // Only failed invoices can be retried
+    if store.lock().await.find_id(&id)?.unpack().status != InvoiceStatus::Failed {
+      return Err(ServiceError::bad_request(
+        "Csak sikertelen számla küldhető be újra!",
+      ));
+    }
 
-  info!("Server started!");
+    let customer = r
+      .customer
+      .map(|c| invoice::Customer::new(c.name, c.tax_number, c.zip, c.location, c.street));
 
-  // Create pdf folder path if not exist
-  std::fs::create_dir_all(format!("data/{}", PDF_FOLDER_NAME))
-    .expect("Error while creating PDF folder path");
+    // Get the original invoice object
+    // and update its customer if there is a corrected one
+    {
+      let mut invoice_objects = self.invoice_object_store.lock().await;
+      let invoice_object = invoice_objects
+        .find_id_mut(&id)
+        .map_err(|_| ServiceError::not_found("A számla eredeti adatai nem találhatóak!"))?;
+      if let Some(c) = &customer {
+        invoice_object.as_mut().unpack().customer = c.clone();
+      }
+    }
 
-  // Channels for new invoice requests
-  let (mut new_invoice_sender, new_invoice_rx) = mpsc::channel::<invoice::InvoiceObject>(100);
+    // Set pending status, with the corrected customer
+    let res = {
+      let mut invoice_store = store.lock().await;
+      let mut invoice = invoice_store.find_id_mut(&id)?.as_mut();
+      let invoice = invoice.unpack();
+      invoice.resubmit()?;
+      if let Some(c) = &customer {
+        invoice.customer = c.clone();
+      }
+      invoice.clone()
+    };
 
-  // Load Invoice Object Store (New invoice requests)
-  let invoice_object_store: Arc<Mutex<VecPack<invoice::InvoiceObject>>> = Arc::new(Mutex::new(
-    VecPack::load_or_init(PathBuf::from("data/invoice_objects"))
-      .expect("Error loading invoice objects storage"),
-  ));
+    // Index is built again with the corrected tax number
+    if customer.is_some() {
+      *store_index.lock().await = index::InvoiceIndex::new();
+    }
 
-  // Load Invoices storage (Done)
-  let invoice_store: Arc<Mutex<VecPack<invoice::Invoice>>> = Arc::new(Mutex::new(
-    VecPack::load_or_init(PathBuf::from("data/invoices")).expect("Error loading invoices storage"),
-  ));
+    // Notify processor to create it
+    self.processor_notify.notify_one();
+    self
+      .publish_status(&InvoiceStatus::Failed, res.clone())
+      .await;
 
-  let agent = szamlazzhu::SzamlazzHu::new();
+    Ok(res.into())
+  }
 
-  let invoice_object_store_clone = invoice_object_store.clone();
-  let invoice_store_clone = invoice_store.clone();
+  async fn create_corrective(&self, r: CorrectiveForm) -> ServiceResult<InvoiceData> {
+    let reference_id = Uuid::parse_str(&r.reference_id)
+      .map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
 
-  // Parallel thread for invoice processor
-  tokio::spawn(async move {
-    // Start invoice processor
-    InvoiceProcessor::new(agent)
-      .start(
-        new_invoice_rx,
-        invoice_object_store_clone,
-        invoice_store_clone,
-      )
-      .await;
-  });
+    let header = invoice::Header::new(
+      parse_date(&r.date)?,
+      parse_date(&r.completion_date)?,
+      parse_date(&r.payment_duedate)?,
+      payment_method(r.payment_kind)?,
+    );
 
-  // Send unprocessed invoice objects to processor
-  for invoice in invoice_object_store.lock().await.iter() {
-    let _ = new_invoice_sender.send(invoice.unpack().clone()).await;
-  }
+    let items = r
+      .items
+      .iter()
+      .map(map_item)
+      .collect::<ServiceResult<Vec<invoice::Item>>>()?;
 
-  let addr = env::var("SERVICE_ADDR_INVOICE")
-    .unwrap_or("[::1]:50060".into())
-    .parse()
-    .unwrap();
+    let (i, invoice_object) = {
+      let mut invoice_store = self.invoice_store.lock().await;
 
-  // Create shutdown channel
-  let (tx, rx) = oneshot::channel();
+      let original = invoice_store.find_id(&reference_id)?.unpack().clone();
 
-  let invoice_service = InvoiceService::new(
-    new_invoice_sender.clone(),
-    invoice_store.clone(),
-    invoice_object_store.clone(),
-  );
+      // Customer of the original invoice,
+      // unless there is a corrected one
+      let customer = match r.customer {
+        Some(c) => invoice::Customer::new(c.name, c.tax_number, c.zip, c.location, c.street),
+        None => original.customer.clone(),
+      };
 
-  // Spawn the server into a runtime
-  tokio::task::spawn(async move {
-    Server::builder()
-      .add_service(invoice_server::InvoiceServer::new(invoice_service))
-      .serve_with_shutdown(addr, async {
-        let _ = rx.await;
-      })
-      .await
-      .unwrap()
-  });
+      // Valid corrections of the same invoice
+      let corrections = invoice_store
+        .iter()
+        .filter(|inv| {
+          inv.unpack().kind == InvoiceKind::Corrective
+            && inv.unpack().reference_id == Some(reference_id)
+            && inv.unpack().is_valid()
+        })
+        .map(|inv| inv.unpack().clone())
+        .collect::<Vec<invoice::Invoice>>();
 
-  tokio::signal::ctrl_c().await?;
+      original.validate_correction(&items, &corrections)?;
 
-  println!("SIGINT");
+      let invoice_object =
+        invoice::InvoiceObject::new_corrective(&original, customer, header, items, r.created_by);
+      let i: invoice::Invoice = invoice_object.clone().into();
 
-  // Send shutdown signal after SIGINT received
-  let _ = tx.send(());
+      invoice_store
+        .insert(i.clone())
+        .map_err(|_| ServiceError::internal_error("Error while saving invoice to invoice store"))?;
 
-  Ok(())
+      (i, invoice_object)
+    };
+
+    if let Err(e) = self.enqueue(invoice_object).await {
+      self.set_enqueue_failed(&self.invoice_store, &i.id).await?;
+      return Err(e);
+    }
+
+    Ok(i.into())
+  }
+
+  async fn register_payment(&self, r: PaymentForm) -> ServiceResult<InvoiceData> {
+    let id = Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
+
+    let payment = invoice::Payment::new(
+      parse_date(&r.date)?,
+      r.amount,
+      payment_method(r.payment_kind)?,
+      r.created_by,
+    );
+
+    // Payments of an invoice are registered one by one,
+    // so together they cannot exceed its outstanding amount
+    let invoice = {
+      let invoice_store = self.invoice_store.lock().await;
+      let invoice = invoice_store.find_id(&id)?.unpack().clone();
+      invoice.check_payment(&payment)?;
+      if !self.payment_reservations.lock().await.insert(id) {
+        return Err(ServiceError::bad_request(
+          "A számla egy másik kifizetésének rögzítése folyamatban van!",
+        ));
+      }
+      invoice
+    };
+
+    let res = self.record_payment(&id, &invoice, payment).await;
+    self.payment_reservations.lock().await.remove(&id);
+
+    Ok(res?.into())
+  }
+
+  /// Register the checked payment by the agent,
+  /// and record it in the payment ledger
+  async fn record_payment(
+    &self,
+    id: &Uuid,
+    invoice: &invoice::Invoice,
+    payment: invoice::Payment,
+  ) -> ServiceResult<invoice::Invoice> {
+    self.rate_limiter.wait().await;
+    self
+      .invoice_agent
+      .register_payment(
+        &invoice.invoice_id.clone().unwrap_or_default(),
+        payment.clone(),
+      )
+      .await?;
+
+    // Payment is registered by the agent,
+    // so we record it without checking it again
+    let mut invoice_store = self.invoice_store.lock().await;
+    let mut invoice = invoice_store.find_id_mut(id)?.as_mut();
+    let invoice = invoice.unpack();
+    invoice.add_payment(payment);
+    Ok(invoice.clone())
+  }
+
+  async fn cancel_invoice(&self, r: CancelRequest) -> ServiceResult<InvoiceData> {
+    let id = Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
+
+    // Proformas not yet issued can be cancelled as well
+    let store = if self.proforma_store.lock().await.find_id(&id).is_ok() {
+      &self.proforma_store
+    } else {
+      &self.invoice_store
+    };
+
+    let invoice = store.lock().await.find_id(&id)?.unpack().clone();
+
+    match invoice.status {
+      // Not yet issued, so we just drop the request
+      InvoiceStatus::Queued | InvoiceStatus::Failed => {
+        let res = {
+          let mut invoice_store = store.lock().await;
+          let mut invoice = invoice_store.find_id_mut(&id)?.as_mut();
+          let invoice = invoice.unpack();
+          invoice.set_status(InvoiceStatus::Cancelled)?;
+          invoice.clone()
+        };
+
+        if let Err(e) = self.invoice_object_store.lock().await.remove_pack(&id) {
+          error!(
+            "Error while removing invoice object from storage: {}; {}",
+            id, e
+          );
+        }
+        self.publish_status(&invoice.status, res.clone()).await;
+
+        // Cancelled storno or final invoice releases its referenced
+        // invoice or proforma, so it can be stornoed or finalized again
+        match (&res.kind, res.reference_id) {
+          (InvoiceKind::Storno, Some(reference_id)) => {
+            self
+              .invoice_store
+              .lock()
+              .await
+              .find_id_mut(&reference_id)?
+              .as_mut()
+              .unpack()
+              .storno_id = None;
+          }
+          (InvoiceKind::Normal, Some(reference_id)) => {
+            self
+              .proforma_store
+              .lock()
+              .await
+              .find_id_mut(&reference_id)?
+              .as_mut()
+              .unpack()
+              .final_id = None;
+          }
+          // Cancelled final invoice releases its deposits
+          (InvoiceKind::Final, _) => {
+            let mut invoice_store = self.invoice_store.lock().await;
+            for deposit_id in &res.deposit_ids {
+              invoice_store
+                .find_id_mut(deposit_id)?
+                .as_mut()
+                .unpack()
+                .final_id = None;
+            }
+          }
+          _ => (),
+        }
+
+        Ok(res.into())
+      }
+      _ if invoice.kind == InvoiceKind::Proforma => Err(ServiceError::bad_request(
+        "Kiállított díjbekérő nem sztornózható!",
+      )),
+      // Issued invoice is cancelled by a storno invoice
+      _ if invoice.can_storno() => {
+        let (storno_object, storno) = {
+          let mut invoice_store = self.invoice_store.lock().await;
+
+          // Check it again under the lock,
+          // so concurrent requests cannot storno it twice
+          let invoice = invoice_store.find_id(&id)?.unpack().clone();
+          if !invoice.can_storno() {
+            return Err(ServiceError::bad_request(
+              "A számla ebben az állapotban nem sztornózható!",
+            ));
+          }
+
+          // Valid corrections would remain without the corrected invoice
+          let mut index = self.invoice_index.lock().await;
+          index.refresh(&invoice_store);
+          if index
+            .purchase_invoices(&invoice_store, &invoice.purchase_id)
+            .iter()
+            .any(|c| {
+              c.kind == InvoiceKind::Corrective && c.reference_id == Some(id) && c.is_valid()
+            })
+          {
+            return Err(ServiceError::bad_request(
+              "A számlához helyesbítő számla tartozik, ezért nem sztornózható!",
+            ));
+          }
+          drop(index);
+
+          let storno_object = invoice::InvoiceObject::new_storno(
+            &invoice,
+            Utc::now().naive_utc().date(),
+            r.created_by,
+          );
+          let storno: invoice::Invoice = storno_object.clone().into();
+          invoice_store.insert(storno.clone()).map_err(|_| {
+            ServiceError::internal_error("Error while saving invoice to invoice store")
+          })?;
+          invoice_store.find_id_mut(&id)?.as_mut().unpack().storno_id = Some(storno.id);
+          (storno_object, storno)
+        };
+
+        // If it fails, then we mark the storno as failed,
+        // so it can be cancelled and requested again.
+        if let Err(e) = self.enqueue(storno_object).await {
+          self
+            .set_enqueue_failed(&self.invoice_store, &storno.id)
+            .await?;
+          return Err(e);
+        }
+
+        Ok(storno.into())
+      }
+      _ => Err(ServiceError::bad_request(
+        "A számla ebben az állapotban nem sztornózható!",
+      )),
+    }
+  }
+
+  async fn create_receipt(&self, r: ReceiptForm) -> ServiceResult<ReceiptData> {
+    let items = r
+      .items
+      .iter()
+      .map(map_item)
+      .collect::<ServiceResult<Vec<invoice::Item>>>()?;
+
+    let mut receipt = receipt::Receipt::new(
+      r.purchase_id,
+      payment_method(r.payment_kind)?,
+      items,
+      r.total_net,
+      r.total_vat,
+      r.total_gross,
+      r.created_by,
+    );
+
+    // Receipt of an earlier request whose answer was lost
+    // might be issued already, so it is looked up first
+    if let Some(issued) = self.resolve_receipts(&receipt.purchase_id).await? {
+      return Ok(issued.into());
+    }
+
+    // Store receipt before submitting it,
+    // so it cannot be lost.
+    // Checked under the same lock, so a purchase has only one receipt.
+    {
+      let mut store = self.receipt_store.lock().await;
+      if store.iter().any(|r| {
+        let r = r.unpack();
+        r.purchase_id == receipt.purchase_id
+          && matches!(
+            r.status,
+            receipt::ReceiptStatus::Submitting | receipt::ReceiptStatus::Issued
+          )
+      }) {
+        return Err(ServiceError::bad_request(
+          "A vásárláshoz már tartozik nyugta!",
+        ));
+      }
+      store
+        .insert(receipt.clone())
+        .map_err(|_| ServiceError::internal_error("Error while saving receipt to receipt store"))?;
+    }
+
+    // Lost requests are sent again with the same call ID,
+    // so szamlazz.hu cannot issue the receipt twice
+    let mut attempt = 1;
+    let result = loop {
+      self.rate_limiter.wait().await;
+      match self.receipt_agent.create_receipt(receipt.clone()).await {
+        Err(e) if e.is_retryable() && attempt < RECEIPT_ATTEMPTS => {
+          error!(
+            "Receipt creation error, sending it again: {}; {}",
+            receipt.id, e
+          );
+          attempt += 1;
+        }
+        res => break res,
+      }
+    };
+
+    // Call ID is taken by an earlier attempt whose answer was lost,
+    // so the receipt is issued already
+    let result = match result {
+      Err(e @ invoice::AgentError::Duplicate(_, _)) if attempt > 1 => {
+        self.rate_limiter.wait().await;
+        match self.receipt_agent.find_receipt(&receipt.call_id()).await {
+          Ok(Some(summary)) => Ok(summary),
+          _ => Err(e),
+        }
+      }
+      res => res,
+    };
+
+    match &result {
+      Ok(summary) => {
+        // PDF can be downloaded later again
+        if let Err(e) =
+          file::save_receipt_pdf(&self.pdf_folder, &summary.receipt_id, &summary.pdf_base64).await
+        {
+          error!("Receipt PDF SAVE ERROR: {}; {}", summary.receipt_id, e);
+        }
+        receipt.set_issued(summary.receipt_id.clone());
+      }
+      // Lost request might be issued, or its call ID is taken
+      // by an earlier attempt, so it is not failed
+      Err(e)
+        if e.is_retryable()
+          || (attempt > 1 && matches!(e, invoice::AgentError::Duplicate(_, _))) =>
+      {
+        error!("Receipt creation is unresolved: {}; {}", receipt.id, e);
+        receipt.set_unresolved(e.into());
+      }
+      Err(e) => {
+        error!("Receipt creation error: {}; {}", receipt.id, e);
+        receipt.set_failed(e.into());
+      }
+    }
+
+    *self
+      .receipt_store
+      .lock()
+      .await
+      .find_id_mut(&receipt.id)?
+      .as_mut()
+      .unpack() = receipt.clone();
+
+    result?;
+
+    Ok(receipt.into())
+  }
+
+  /// Look up the unresolved receipts of the purchase by their call IDs
+  /// Returns the one found issued; the ones not found are set failed.
+  async fn resolve_receipts(&self, purchase_id: &str) -> ServiceResult<Option<receipt::Receipt>> {
+    let unresolved = self
+      .receipt_store
+      .lock()
+      .await
+      .iter()
+      .map(|r| r.unpack())
+      .filter(|r| {
+        r.purchase_id == purchase_id
+          && r.status == receipt::ReceiptStatus::Submitting
+          && r.failure.is_some()
+      })
+      .cloned()
+      .collect::<Vec<receipt::Receipt>>();
+
+    for mut receipt in unresolved {
+      self.rate_limiter.wait().await;
+      let found = self.receipt_agent.find_receipt(&receipt.call_id()).await?;
+      match &found {
+        Some(summary) => {
+          if let Err(e) =
+            file::save_receipt_pdf(&self.pdf_folder, &summary.receipt_id, &summary.pdf_base64).await
+          {
+            error!("Receipt PDF SAVE ERROR: {}; {}", summary.receipt_id, e);
+          }
+          receipt.set_issued(summary.receipt_id.clone());
+        }
+        None => {
+          if let Some(failure) = receipt.failure.clone() {
+            receipt.set_failed(failure);
+          }
+        }
+      }
+      *self
+        .receipt_store
+        .lock()
+        .await
+        .find_id_mut(&receipt.id)?
+        .as_mut()
+        .unpack() = receipt.clone();
+      if found.is_some() {
+        return Ok(Some(receipt));
+      }
+    }
+    Ok(None)
+  }
+
+  async fn get_receipt_by_id(&self, r: ByIdRequest) -> ServiceResult<ReceiptData> {
+    let id = Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
+    let res = self
+      .receipt_store
+      .lock()
+      .await
+      .find_id(&id)?
+      .unpack()
+      .clone();
+
+    Ok(res.into())
+  }
+
+  async fn cancel_receipt(&self, r: CancelRequest) -> ServiceResult<ReceiptData> {
+    let id = Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
+
+    let mut receipt = self
+      .receipt_store
+      .lock()
+      .await
+      .find_id(&id)?
+      .unpack()
+      .clone();
+
+    receipt.can_cancel()?;
+
+    self.rate_limiter.wait().await;
+    let summary = self
+      .receipt_agent
+      .cancel_receipt(&receipt.receipt_id.clone().unwrap_or_default())
+      .await?;
+
+    if let Err(e) =
+      file::save_receipt_pdf(&self.pdf_folder, &summary.receipt_id, &summary.pdf_base64).await
+    {
+      error!("Receipt PDF SAVE ERROR: {}; {}", summary.receipt_id, e);
+    }
+
+    receipt.set_cancelled(summary.receipt_id, r.created_by);
+
+    *self
+      .receipt_store
+      .lock()
+      .await
+      .find_id_mut(&id)?
+      .as_mut()
+      .unpack() = receipt.clone();
+
+    Ok(receipt.into())
+  }
+
+  async fn download_receipt(&self, r: ByIdRequest) -> ServiceResult<DownloadResponse> {
+    let id = Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
+
+    let receipt_id = self
+      .receipt_store
+      .lock()
+      .await
+      .find_id(&id)?
+      .unpack()
+      .receipt_id
+      .clone()
+      .ok_or_else(|| ServiceError::not_found("A nyugta még nincs kiállítva!"))?;
+
+    match file::load_invoice_base64(&self.pdf_folder, &receipt_id).await {
+      Ok(pdf_base64) => Ok(DownloadResponse { pdf_base64 }),
+      // Download it again from the receipt agent
+      Err(file::FileError::NotFound) => {
+        self.rate_limiter.wait().await;
+        let summary = self.receipt_agent.get_receipt(&receipt_id).await?;
+        if let Err(e) =
+          file::save_receipt_pdf(&self.pdf_folder, &receipt_id, &summary.pdf_base64).await
+        {
+          error!("Receipt PDF SAVE ERROR: {}; {}", receipt_id, e);
+        }
+        Ok(DownloadResponse {
+          pdf_base64: summary.pdf_base64.replace("\n", ""),
+        })
+      }
+      Err(e) => Err(ServiceError::internal_error(&e.to_string())),
+    }
+  }
+
+  async fn get_receivables(&self, r: ReceivablesRequest) -> ServiceResult<ReceivablesResponse> {
+    let today = if r.date.is_empty() {
+      Utc::now().naive_utc().date()
+    } else {
+      parse_date(&r.date)?
+    };
+
+    let customers = report::receivables(
+      self.invoice_store.lock().await.iter().map(|i| i.unpack()),
+      today,
+      r.overdue_only,
+    );
+
+    Ok(ReceivablesResponse {
+      total_outstanding: customers.iter().map(|c| c.outstanding).sum(),
+      total_overdue: customers.iter().map(|c| c.overdue).sum(),
+      customers: customers.into_iter().map(|c| c.into()).collect(),
+    })
+  }
+
+  /// Invoices and proformas of the given purchases in creation order
+  async fn purchase_invoices(&self, purchase_ids: &[String]) -> Vec<Vec<invoice::Invoice>> {
+    let mut res: Vec<Vec<invoice::Invoice>> = {
+      let store = self.invoice_store.lock().await;
+      let mut index = self.invoice_index.lock().await;
+      index.refresh(&store);
+      purchase_ids
+        .iter()
+        .map(|id| index.purchase_invoices(&store, id))
+        .collect()
+    };
+    let store = self.proforma_store.lock().await;
+    let mut index = self.proforma_index.lock().await;
+    index.refresh(&store);
+    for (invoices, id) in res.iter_mut().zip(purchase_ids) {
+      invoices.extend(index.purchase_invoices(&store, id));
+      invoices.sort_by_key(|i| (i.created_at, i.id));
+    }
+    res
+  }
+
+  async fn get_by_purchase_id(&self, r: ByPurchaseIdRequest) -> ServiceResult<InvoicesResponse> {
+    let invoices = self
+      .purchase_invoices(std::slice::from_ref(&r.purchase_id))
+      .await
+      .pop()
+      .unwrap_or_default();
+
+    Ok(InvoicesResponse {
+      invoices: invoices.into_iter().map(|i| i.into()).collect(),
+    })
+  }
+
+  async fn get_by_purchase_ids(
+    &self,
+    r: ByPurchaseIdsRequest,
+  ) -> ServiceResult<PurchaseInvoicesResponse> {
+    let invoices = self.purchase_invoices(&r.purchase_ids).await;
+
+    Ok(PurchaseInvoicesResponse {
+      purchases: r
+        .purchase_ids
+        .into_iter()
+        .zip(invoices)
+        .map(
+          |(purchase_id, invoices)| purchase_invoices_response::PurchaseInvoices {
+            invoices: invoices.into_iter().map(|i| i.into()).collect(),
+            purchase_id,
+          },
+        )
+        .collect(),
+    })
+  }
+
+  async fn search_invoices(&self, r: SearchRequest) -> ServiceResult<SearchResponse> {
+    let parse_day = |datestr: &str| -> ServiceResult<Option<NaiveDate>> {
+      if datestr.is_empty() {
+        return Ok(None);
+      }
+      NaiveDate::parse_from_str(datestr, "%Y-%m-%d")
+        .map(Some)
+        .map_err(|_| ServiceError::bad_request("A megadott dátum hibás"))
+    };
+    let non_empty = |s: String| if s.is_empty() { None } else { Some(s) };
+
+    let filter = index::InvoiceFilter {
+      date_from: parse_day(&r.date_from)?,
+      date_to: parse_day(&r.date_to)?,
+      purchase_id: non_empty(r.purchase_id),
+      invoice_id: non_empty(r.invoice_id),
+      customer: non_empty(r.customer),
+      statuses: r
+        .statuses
+        .iter()
+        .map(|s| {
+          invoice_data::Status::from_i32(*s)
+            .map(|s| s.into())
+            .ok_or_else(|| ServiceError::bad_request("Hibás számla státusz"))
+        })
+        .collect::<ServiceResult<Vec<InvoiceStatus>>>()?,
+      created_by: if r.created_by == 0 {
+        None
+      } else {
+        Some(r.created_by)
+      },
+      payment_methods: r
+        .payment_kinds
+        .iter()
+        .map(|k| payment_method(*k))
+        .collect::<ServiceResult<Vec<PaymentMethod>>>()?,
+    };
+    let cursor = match r.cursor.as_str() {
+      "" => None,
+      c => Some(
+        index::Cursor::parse(c)
+          .ok_or_else(|| ServiceError::bad_request("Hibás lapozási kurzor"))?,
+      ),
+    };
+    let page_size = match r.page_size {
+      0 => 50,
+      n => n.min(500) as usize,
+    };
+
+    let store = self.invoice_store.lock().await;
+    let mut index = self.invoice_index.lock().await;
+    index.refresh(&store);
+    let (invoices, next_cursor) = index.search(&store, &filter, cursor, page_size);
+
+    Ok(SearchResponse {
+      invoices: invoices.into_iter().map(|i| i.into()).collect(),
+      next_cursor: next_cursor.map(|c| c.to_string()).unwrap_or_default(),
+    })
+  }
+
+  async fn get_webhook_deliveries(
+    &self,
+    r: WebhookDeliveriesRequest,
+  ) -> ServiceResult<WebhookDeliveriesResponse> {
+    let deliveries = self
+      .webhook_log
+      .deliveries
+      .lock()
+      .await
+      .iter()
+      .map(|d| d.unpack().clone())
+      .filter(|d| !r.failed_only || d.status == webhook::DeliveryStatus::Failed)
+      .map(|d| d.into())
+      .collect();
+
+    Ok(WebhookDeliveriesResponse { deliveries })
+  }
+
+  async fn replay_webhooks(
+    &self,
+    r: ReplayWebhooksRequest,
+  ) -> ServiceResult<ReplayWebhooksResponse> {
+    let replayed = {
+      let mut deliveries = self.webhook_log.deliveries.lock().await;
+      if r.id.is_empty() {
+        deliveries
+          .as_vec_mut()
+          .iter_mut()
+          // Only the failed ones are touched, so only those are saved
+          .filter(|d| d.unpack().status == webhook::DeliveryStatus::Failed)
+          .map(|d| d.as_mut().unpack().replay())
+          .count() as u32
+      } else {
+        let id =
+          Uuid::parse_str(&r.id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
+        if !deliveries.find_id_mut(&id)?.as_mut().unpack().replay() {
+          return Err(ServiceError::bad_request(
+            "Csak sikertelen webhook küldés ismételhető!",
+          ));
+        }
+        1
+      }
+    };
+
+    self.webhook_log.notify.notify_one();
+
+    Ok(ReplayWebhooksResponse { replayed })
+  }
+
+  async fn subscribe_status(
+    &self,
+    r: SubscribeRequest,
+  ) -> ServiceResult<ReceiverStream<Result<InvoiceData, Status>>> {
+    // Subscribe first, so no change is lost
+    // between the current state and the subscription
+    let mut status_rx = self.status_tx.subscribe();
+    let (tx, rx) = mpsc::channel(STATUS_CHANNEL_SIZE);
+
+    let id = match r.id.as_str() {
+      "" => None,
+      id => {
+        let id =
+          Uuid::parse_str(id).map_err(|_| ServiceError::bad_request("Hibás ID! Nem UUID"))?;
+        let current = match self.proforma_store.lock().await.find_id(&id) {
+          Ok(i) => i.unpack().clone(),
+          Err(_) => self
+            .invoice_store
+            .lock()
+            .await
+            .find_id(&id)?
+            .unpack()
+            .clone(),
+        };
+        let _ = tx.send(Ok(current.into())).await;
+        Some(id)
+      }
+    };
+
+    let invoices = self.invoice_store.clone();
+    let proformas = self.proforma_store.clone();
+    tokio::spawn(async move {
+      loop {
+        tokio::select! {
+          res = status_rx.recv() => match res {
+            Ok(i) => {
+              if id.is_some_and(|id| id != i.id) {
+                continue;
+              }
+              if tx.send(Ok(i.into())).await.is_err() {
+                break;
+              }
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+              warn!("Status subscriber lagged behind, {} changes skipped", n);
+              // Skipped changes might include the final one,
+              // so the current status of the invoice is sent again
+              let current = match id {
+                Some(id) => match proformas.lock().await.find_id(&id) {
+                  Ok(i) => Some(i.unpack().clone()),
+                  Err(_) => invoices
+                    .lock()
+                    .await
+                    .find_id(&id)
+                    .ok()
+                    .map(|i| i.unpack().clone()),
+                },
+                None => None,
+              };
+              if let Some(current) = current {
+                if tx.send(Ok(current.into())).await.is_err() {
+                  break;
+                }
+              }
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+          },
+          // Subscriber is gone
+          _ = tx.closed() => break,
+        }
+      }
+    });
+
+    Ok(ReceiverStream::new(rx))
+  }
+
+  async fn health(&self, _r: HealthRequest) -> ServiceResult<HealthResponse> {
+    let processor_running = self.processor_running.load(Ordering::SeqCst);
+
+    let (mut queued, mut pdf_missing, mut failed) = (0, 0, 0);
+    self
+      .invoice_store
+      .lock()
+      .await
+      .iter()
+      .for_each(|i| match i.unpack().status {
+        InvoiceStatus::Queued | InvoiceStatus::Submitting => queued += 1,
+        InvoiceStatus::PdfMissing => pdf_missing += 1,
+        InvoiceStatus::Failed => failed += 1,
+        _ => (),
+      });
+
+    Ok(HealthResponse {
+      is_healthy: processor_running && pdf_missing == 0,
+      processor_running,
+      queued,
+      pdf_missing,
+      failed,
+    })
+  }
+
+  async fn download(&self, r: DownloadRequest) -> ServiceResult<DownloadResponse> {
+    let pdf_base64 = file::load_invoice_base64(&self.pdf_folder, &r.invoice_id)
+      .await
+      .map_err(|e| ServiceError::internal_error(&e.to_string()))?;
+
+    Ok(DownloadResponse { pdf_base64 })
+  }
+}
+
+/// Invoice object from the given invoice form
+fn invoice_object_from_form(r: InvoiceForm) -> ServiceResult<invoice::InvoiceObject> {
+  // Create seller
+  let seller = invoice::Seller::new();
+
+  // Create customer
+  let c = match r.customer {
+    Some(_customer) => _customer,
+    None => return Err(ServiceError::internal_error("Missing customer object")),
+  };
+  let customer = invoice::Customer::new(c.name, c.tax_number, c.zip, c.location, c.street);
+
+  let header = invoice::Header::new(
+    parse_date(&r.date)?,
+    parse_date(&r.completion_date)?,
+    parse_date(&r.payment_duedate)?,
+    payment_method(r.payment_kind)?,
+  );
+
+  let items = r
+    .items
+    .iter()
+    .map(map_item)
+    .collect::<ServiceResult<Vec<invoice::Item>>>()?;
+
+  // Create Invoice Object
+  Ok(invoice::InvoiceObject::new(
+    r.purchase_id,
+    seller,
+    customer,
+    header,
+    items,
+    r.total_net,
+    r.total_gross,
+    r.total_vat,
+    chrono::Utc::now(),
+    r.created_by,
+  ))
+}
+
+/// Check if there is any valid invoice for the given purchase ID
+/// Only normal and final invoices count
+/// Called under the invoice store lock
+async fn has_valid_invoice(
+  invoice_store: &VecPack<invoice::Invoice>,
+  invoice_index: &Mutex<index::InvoiceIndex>,
+  purchase_id: &str,
+) -> bool {
+  let mut index = invoice_index.lock().await;
+  index.refresh(invoice_store);
+  index.has_valid_invoice(invoice_store, purchase_id)
+}
+
+/// DateTime RFC3339 to NaiveDate
+fn parse_date(datestr: &str) -> ServiceResult<NaiveDate> {
+  let date = DateTime::parse_from_rfc3339(datestr)
+    .map_err(|_| ServiceError::internal_error("A megadott dátum hibás"))?
+    .with_timezone(&Utc);
+  Ok(date.naive_utc().date())
+}
+
+fn payment_method(payment_kind: i32) -> ServiceResult<PaymentMethod> {
+  let payment_kind: PaymentKind = PaymentKind::from_i32(payment_kind)
+    .ok_or(ServiceError::internal_error("Wrong paymentkind ENUM!"))?;
+  Ok(match payment_kind {
+    PaymentKind::Cash => PaymentMethod::Cash,
+    PaymentKind::Card => PaymentMethod::Card,
+    PaymentKind::Transfer => PaymentMethod::Transfer,
+  })
+}
+
+fn map_item(i: &invoice_form::Item) -> ServiceResult<invoice::Item> {
+  invoice::Item::new(
+    i.name.to_string(),
+    i.quantity,
+    i.unit.to_string(),
+    i.price_unit_net,
+    invoice::VAT::from_str(&i.vat).map_err(|e| ServiceError::bad_request(&e))?,
+    i.total_price_net,
+    i.total_price_vat,
+    i.total_price_gross,
+  )
+  .map_err(|_| {
+    ServiceError::bad_request(&format!(
+      "A megadott tétel ár adatai (nettó, áfa, bruttó) nem helyesek! {:?}",
+      i
+    ))
+  })
+}
+
+#[tonic::async_trait]
+impl invoice_server::Invoice for InvoiceService {
+  async fn create_new(
+    &self,
+    request: Request<InvoiceForm>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.create_new(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_by_id(
+    &self,
+    request: Request<ByIdRequest>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.get_by_id(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn download(
+    &self,
+    request: Request<DownloadRequest>,
+  ) -> Result<Response<DownloadResponse>, Status> {
+    let res = self.download(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_by_purchase_id(
+    &self,
+    request: Request<ByPurchaseIdRequest>,
+  ) -> Result<Response<InvoicesResponse>, Status> {
+    let res = self.get_by_purchase_id(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_by_purchase_ids(
+    &self,
+    request: Request<ByPurchaseIdsRequest>,
+  ) -> Result<Response<PurchaseInvoicesResponse>, Status> {
+    let res = self.get_by_purchase_ids(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_invoice(
+    &self,
+    request: Request<ByIdRequest>,
+  ) -> Result<Response<InvoiceDetails>, Status> {
+    let res = self.get_invoice(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn retry_invoice(
+    &self,
+    request: Request<RetryRequest>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.retry_invoice(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn create_corrective(
+    &self,
+    request: Request<CorrectiveForm>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.create_corrective(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn create_proforma(
+    &self,
+    request: Request<InvoiceForm>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.create_proforma(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_proforma_by_id(
+    &self,
+    request: Request<ByIdRequest>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.get_proforma_by_id(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn finalize_proforma(
+    &self,
+    request: Request<FinalizeProformaRequest>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.finalize_proforma(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn create_deposit(
+    &self,
+    request: Request<InvoiceForm>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.create_deposit(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn create_final(
+    &self,
+    request: Request<FinalInvoiceForm>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.create_final(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn register_payment(
+    &self,
+    request: Request<PaymentForm>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.register_payment(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn cancel_invoice(
+    &self,
+    request: Request<CancelRequest>,
+  ) -> Result<Response<InvoiceData>, Status> {
+    let res = self.cancel_invoice(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn create_receipt(
+    &self,
+    request: Request<ReceiptForm>,
+  ) -> Result<Response<ReceiptData>, Status> {
+    let res = self.create_receipt(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_receipt_by_id(
+    &self,
+    request: Request<ByIdRequest>,
+  ) -> Result<Response<ReceiptData>, Status> {
+    let res = self.get_receipt_by_id(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn cancel_receipt(
+    &self,
+    request: Request<CancelRequest>,
+  ) -> Result<Response<ReceiptData>, Status> {
+    let res = self.cancel_receipt(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn download_receipt(
+    &self,
+    request: Request<ByIdRequest>,
+  ) -> Result<Response<DownloadResponse>, Status> {
+    let res = self.download_receipt(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_receivables(
+    &self,
+    request: Request<ReceivablesRequest>,
+  ) -> Result<Response<ReceivablesResponse>, Status> {
+    let res = self.get_receivables(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn search_invoices(
+    &self,
+    request: Request<SearchRequest>,
+  ) -> Result<Response<SearchResponse>, Status> {
+    let res = self.search_invoices(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_webhook_deliveries(
+    &self,
+    request: Request<WebhookDeliveriesRequest>,
+  ) -> Result<Response<WebhookDeliveriesResponse>, Status> {
+    let res = self.get_webhook_deliveries(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn replay_webhooks(
+    &self,
+    request: Request<ReplayWebhooksRequest>,
+  ) -> Result<Response<ReplayWebhooksResponse>, Status> {
+    let res = self.replay_webhooks(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  type SubscribeStatusStream = ReceiverStream<Result<InvoiceData, Status>>;
+
+  async fn subscribe_status(
+    &self,
+    request: Request<SubscribeRequest>,
+  ) -> Result<Response<Self::SubscribeStatusStream>, Status> {
+    let res = self.subscribe_status(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn health(
+    &self,
+    request: Request<HealthRequest>,
+  ) -> Result<Response<HealthResponse>, Status> {
+    let res = self.health(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+  pretty_env_logger::init();
+
+  // CLI commands run against the running service
+  // e.g. invoice_microservice receivables --overdue
+  // or invoice_microservice mock-szamlazzhu 127.0.0.1:8090
+  let args = env::args().skip(1).collect::<Vec<String>>();
+  if cli::run(&args).await? {
+    return Ok(());
+  }
+
+  info!("Server started!");
+
+  // Create pdf folder path if not exist
+  let pdf_folder = PathBuf::from("data").join(PDF_FOLDER_NAME);
+  std::fs::create_dir_all(&pdf_folder).expect("Error while creating PDF folder path");
+
+  // Notify processor about new invoice requests
+  let processor_notify = Arc::new(Notify::new());
+
+  // Load Invoice Object Store (New invoice requests)
+  // Invoice objects of the first release are upgraded
+  let invoice_object_store: Arc<Mutex<VecPack<invoice::InvoiceObject>>> = Arc::new(Mutex::new(
+    VecPack::try_load_or_init(PathBuf::from("data/invoice_objects"))
+      .expect("Error loading invoice objects storage"),
+  ));
+
+  // Load Invoices storage (Done)
+  // Invoices of the first release are upgraded
+  let invoice_store: Arc<Mutex<VecPack<invoice::Invoice>>> = Arc::new(Mutex::new(
+    VecPack::try_load_or_init(PathBuf::from("data/invoices"))
+      .expect("Error loading invoices storage"),
+  ));
+
+  // Load Proforma storage
+  let proforma_store: Arc<Mutex<VecPack<invoice::Invoice>>> = Arc::new(Mutex::new(
+    VecPack::load_or_init(PathBuf::from("data/proformas"))
+      .expect("Error loading proformas storage"),
+  ));
+
+  // Load Receipt storage
+  let receipt_store: Arc<Mutex<VecPack<receipt::Receipt>>> = Arc::new(Mutex::new(
+    VecPack::load_or_init(PathBuf::from("data/receipts")).expect("Error loading receipts storage"),
+  ));
+
+  // Load webhook delivery log
+  let webhook_deliveries: Arc<Mutex<VecPack<webhook::Delivery>>> = Arc::new(Mutex::new(
+    VecPack::load_or_init(PathBuf::from("data/webhook_deliveries"))
+      .expect("Error loading webhook deliveries storage"),
+  ));
+
+  let agent = szamlazzhu::SzamlazzHu::new();
+
+  // Agent of the synchronous requests
+  let service_agent = Arc::new(szamlazzhu::SzamlazzHu::new());
+
+  // Every request to szamlazz.hu shares the same rate limiter
+  let rate_limiter = Arc::new(rate_limit::RateLimiter::from_env());
+
+  // Status changes published by the processor and the service
+  let (status_tx, _) = broadcast::channel(STATUS_CHANNEL_SIZE);
+
+  // Webhook deliveries are recorded with the status changes
+  let webhook_config = webhook::WebhookConfig::from_env();
+  let webhook_log = Arc::new(webhook::DeliveryLog::new(
+    webhook_config.endpoints.clone(),
+    webhook_deliveries,
+    Arc::new(Notify::new()),
+  ));
+
+  let worker_count = env::var("INVOICE_WORKER_COUNT")
+    .ok()
+    .and_then(|v| v.trim().parse::<usize>().ok())
+    .unwrap_or(WORKER_MAX);
+
+  let poll_interval = env::var("INVOICE_POLL_INTERVAL_SECS")
+    .ok()
+    .and_then(|v| v.trim().parse::<u64>().ok())
+    .unwrap_or(POLL_INTERVAL_SECS);
+
+  let invoice_processor = Arc::new(processor::InvoiceProcessor::new(
+    agent,
+    retry::RetryPolicy::from_env(),
+    rate_limiter.clone(),
+    processor_notify.clone(),
+    Duration::from_secs(poll_interval),
+    invoice_object_store.clone(),
+    invoice_store.clone(),
+    proforma_store.clone(),
+    status_tx.clone(),
+    webhook_log.clone(),
+    pdf_folder.clone(),
+  ));
+
+  // Webhook notifier delivers the issued and failed invoices
+  // to the configured endpoints
+  if webhook_config.endpoints.is_empty() {
+    info!("No webhook endpoint is set, webhooks are disabled");
+  } else {
+    let webhook_notifier = Arc::new(webhook::WebhookNotifier::new(
+      webhook_config,
+      webhook_log.clone(),
+      Duration::from_secs(poll_interval),
+    ));
+    tokio::spawn(async move { webhook_notifier.start().await });
+  }
+
+  // Parallel thread for invoice processor
+  // Supervisor restarts the processor whenever it stops.
+  // Unprocessed invoice objects are loaded from the invoice object store,
+  // so nothing is lost.
+  let processor_running = Arc::new(AtomicBool::new(false));
+  let processor_running_clone = processor_running.clone();
+  tokio::spawn(async move {
+    loop {
+      let invoice_processor = invoice_processor.clone();
+      // Start invoice processor
+      processor_running_clone.store(true, Ordering::SeqCst);
+      let res = tokio::spawn(async move { invoice_processor.start(worker_count).await }).await;
+      processor_running_clone.store(false, Ordering::SeqCst);
+      match res {
+        Ok(_) => error!("Invoice processor stopped! Restarting..."),
+        Err(e) => error!("Invoice processor crashed! Restarting... {}", e),
+      }
+      tokio::time::sleep(Duration::from_secs(PROCESSOR_RESTART_DELAY_SECS)).await;
+    }
+  });
+
+  let addr = env::var("SERVICE_ADDR_INVOICE")
+    .unwrap_or("[::1]:50060".into())
+    .parse()
+    .unwrap();
+
+  // Create shutdown channel
+  let (tx, rx) = oneshot::channel();
+
+  let invoice_service = InvoiceService::new(
+    processor_notify.clone(),
+    processor_running.clone(),
+    invoice_store.clone(),
+    invoice_object_store.clone(),
+    proforma_store.clone(),
+    receipt_store.clone(),
+    service_agent.clone(),
+    service_agent.clone(),
+    rate_limiter.clone(),
+    status_tx,
+    webhook_log,
+    pdf_folder,
+  );
+
+  // Spawn the server into a runtime
+  tokio::task::spawn(async move {
+    Server::builder()
+      .add_service(invoice_server::InvoiceServer::new(invoice_service))
+      .serve_with_shutdown(addr, async {
+        let _ = rx.await;
+      })
+      .await
+      .unwrap()
+  });
+
+  tokio::signal::ctrl_c().await?;
+
+  println!("SIGINT");
+
+  // Send shutdown signal after SIGINT received
+  let _ = tx.send(());
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Service on empty stores of a temp folder,
+  /// with its agents pointed to a local mock server
+  fn test_service() -> (InvoiceService, Arc<mock::MockState>) {
+    let (addr, mock) = mock::start(([127, 0, 0, 1], 0).into()).unwrap();
+    (service_of(addr), mock)
+  }
+
+  /// Service with its agents pointed to the mock of the given address
+  fn service_of(addr: std::net::SocketAddr) -> InvoiceService {
+    let dir = env::temp_dir().join(format!("invoice_service_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(dir.join(PDF_FOLDER_NAME)).unwrap();
+    let (status_tx, _) = broadcast::channel(STATUS_CHANNEL_SIZE);
+    InvoiceService::new(
+      Arc::new(Notify::new()),
+      Arc::new(AtomicBool::new(true)),
+      Arc::new(Mutex::new(
+        VecPack::load_or_init(dir.join("invoices")).unwrap(),
+      )),
+      Arc::new(Mutex::new(
+        VecPack::load_or_init(dir.join("invoice_objects")).unwrap(),
+      )),
+      Arc::new(Mutex::new(
+        VecPack::load_or_init(dir.join("proformas")).unwrap(),
+      )),
+      Arc::new(Mutex::new(
+        VecPack::load_or_init(dir.join("receipts")).unwrap(),
+      )),
+      Arc::new(szamlazzhu::SzamlazzHu::mock(addr)),
+      Arc::new(szamlazzhu::SzamlazzHu::mock(addr)),
+      Arc::new(rate_limit::RateLimiter::new(Duration::from_millis(0))),
+      status_tx,
+      Arc::new(webhook::DeliveryLog::new(
+        Vec::new(),
+        Arc::new(Mutex::new(
+          VecPack::load_or_init(dir.join("webhook_deliveries")).unwrap(),
+        )),
+        Arc::new(Notify::new()),
+      )),
+      dir.join(PDF_FOLDER_NAME),
+    )
+  }
+
+  fn invoice_form(purchase_id: &str) -> InvoiceForm {
+    InvoiceForm {
+      purchase_id: purchase_id.into(),
+      customer: Some(invoice_form::Customer {
+        name: "Kert Kft.".into(),
+        tax_number: "1234".into(),
+        ..invoice_form::Customer::default()
+      }),
+      payment_duedate: "2021-03-18T00:00:00Z".into(),
+      date: "2021-03-10T00:00:00Z".into(),
+      completion_date: "2021-03-10T00:00:00Z".into(),
+      items: vec![invoice_form::Item {
+        name: "Metszőolló".into(),
+        quantity: 1,
+        unit: "db".into(),
+        price_unit_net: 1000,
+        vat: "27".into(),
+        total_price_net: 1000,
+        total_price_vat: 270,
+        total_price_gross: 1270,
+        ..invoice_form::Item::default()
+      }],
+      total_net: 1000,
+      total_vat: 270,
+      total_gross: 1270,
+      ..InvoiceForm::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn test_create_new_duplicate_purchase() {
+    let (service, _mock) = test_service();
+    let first = service.create_new(invoice_form("p1")).await.unwrap();
+    assert!(matches!(
+      service.create_new(invoice_form("p1")).await,
+      Err(ServiceError::AlreadyExists(_))
+    ));
+    assert!(service.create_new(invoice_form("p2")).await.is_ok());
+
+    // Cancelled invoice releases its purchase
+    service
+      .cancel_invoice(CancelRequest {
+        id: first.id,
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+    assert!(service.create_new(invoice_form("p1")).await.is_ok());
+  }
+
+  /// Issue an invoice by the mock, and store it as the processor does
+  async fn issued_invoice(service: &InvoiceService, purchase_id: &str) -> invoice::Invoice {
+    let invoice_object = invoice::InvoiceObject {
+      cart_id: purchase_id.into(),
+      ..mock::invoice_object()
+    };
+    let summary = service
+      .invoice_agent
+      .create_invoice(invoice_object.clone())
+      .await
+      .unwrap();
+    let mut i: invoice::Invoice = invoice_object.into();
+    i.set_status(InvoiceStatus::Submitting).unwrap();
+    i.invoice_id = Some(summary.invoice_id);
+    i.outstanding = summary.outstanding;
+    i.set_status(InvoiceStatus::Issued).unwrap();
+    service
+      .invoice_store
+      .lock()
+      .await
+      .insert(i.clone())
+      .unwrap();
+    i
+  }
+
+  /// Proforma of the given purchase set issued
+  async fn issued_proforma(service: &InvoiceService, purchase_id: &str) -> Uuid {
+    let created = service
+      .create_proforma(invoice_form(purchase_id))
+      .await
+      .unwrap();
+    let id = Uuid::parse_str(&created.id).unwrap();
+    let mut proformas = service.proforma_store.lock().await;
+    let mut i = proformas.find_id_mut(&id).unwrap().as_mut();
+    let i = i.unpack();
+    i.set_status(InvoiceStatus::Submitting).unwrap();
+    i.invoice_id = Some(format!("D-{}", purchase_id));
+    i.set_status(InvoiceStatus::Issued).unwrap();
+    id
+  }
+
+  #[tokio::test]
+  async fn test_finalize_proforma() {
+    let (service, _mock) = test_service();
+    let proforma_id = issued_proforma(&service, "p1").await;
+    let finalize = || {
+      service.finalize_proforma(FinalizeProformaRequest {
+        id: proforma_id.to_string(),
+        date: "2021-03-12T00:00:00Z".into(),
+        created_by: 1,
+      })
+    };
+
+    let final_invoice = finalize().await.unwrap();
+    let stored = service
+      .invoice_store
+      .lock()
+      .await
+      .find_id(&Uuid::parse_str(&final_invoice.id).unwrap())
+      .unwrap()
+      .unpack()
+      .clone();
+    assert_eq!(stored.reference_id, Some(proforma_id));
+    assert_eq!(stored.total_gross, 1270);
+    // Proforma is not in the invoice store
+    assert!(service
+      .invoice_store
+      .lock()
+      .await
+      .find_id(&proforma_id)
+      .is_err());
+
+    // Purchase cannot be finalized twice
+    assert!(finalize().await.is_err());
+
+    // Cancelled final invoice releases the proforma
+    service
+      .cancel_invoice(CancelRequest {
+        id: final_invoice.id,
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+    let proforma = service
+      .proforma_store
+      .lock()
+      .await
+      .find_id(&proforma_id)
+      .unwrap()
+      .unpack()
+      .clone();
+    assert_eq!(proforma.final_id, None);
+    assert!(finalize().await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_cancel_proforma() {
+    let (service, _mock) = test_service();
+    let queued = service.create_proforma(invoice_form("p1")).await.unwrap();
+    let cancelled = service
+      .cancel_invoice(CancelRequest {
+        id: queued.id.clone(),
+        created_by: 1,
+      })
+      .await
+      .unwrap();
+    assert_eq!(cancelled.status, invoice_data::Status::Cancelled as i32);
+    assert!(service.invoice_object_store.lock().await.is_empty());
+
+    // Issued proforma cannot be stornoed
+    let issued = issued_proforma(&service, "p2").await;
+    assert!(service
+      .cancel_invoice(CancelRequest {
+        id: issued.to_string(),
+        created_by: 1,
+      })
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_get_by_purchase_id_proformas() {
+    let (service, _mock) = test_service();
+    let proforma = issued_proforma(&service, "p1").await;
+    let invoice = issued_invoice(&service, "p1").await;
+    let res = service
+      .get_by_purchase_id(ByPurchaseIdRequest {
+        purchase_id: "p1".into(),
+      })
+      .await
+      .unwrap();
+    let ids: Vec<Uuid> = res
+      .invoices
+      .iter()
+      .map(|i| Uuid::parse_str(&i.id).unwrap())
+      .collect();
+    assert_eq!(ids.len(), 2);
+    assert!(ids.contains(&proforma) && ids.contains(&invoice.id));
+
+    // Only the invoice counts as a valid one
+    assert!(service.create_new(invoice_form("p1")).await.is_err());
+    issued_proforma(&service, "p2").await;
+    assert!(service.create_new(invoice_form("p2")).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_cancel_corrected_invoice() {
+    let (service, _mock) = test_service();
+    let issued = issued_invoice(&service, "p1").await;
+    let corrective: invoice::Invoice = invoice::InvoiceObject::new_corrective(
+      &issued,
+      issued.customer.clone(),
+      issued.header.clone(),
+      Vec::new(),
+      1,
+    )
+    .into();
+    service
+      .invoice_store
+      .lock()
+      .await
+      .insert(corrective.clone())
+      .unwrap();
+    let cancel = || {
+      service.cancel_invoice(CancelRequest {
+        id: issued.id.to_string(),
+        created_by: 1,
+      })
+    };
+    assert!(cancel().await.is_err());
+
+    // Failed correction does not prevent the storno
+    service
+      .invoice_store
+      .lock()
+      .await
+      .find_id_mut(&corrective.id)
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .set_status(InvoiceStatus::Failed)
+      .unwrap();
+    assert!(cancel().await.is_ok());
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_cancel_invoice_concurrently() {
+    let (service, _mock) = test_service();
+    let service = Arc::new(service);
+    let issued = issued_invoice(&service, "p1").await;
+
+    let cancels = (0..8)
+      .map(|_| {
+        let service = service.clone();
+        let id = issued.id.to_string();
+        tokio::spawn(async move {
+          service
+            .cancel_invoice(CancelRequest { id, created_by: 1 })
+            .await
+        })
+      })
+      .collect::<Vec<_>>();
+    let mut stornos = Vec::new();
+    for cancel in cancels {
+      if let Ok(storno) = cancel.await.unwrap() {
+        stornos.push(storno);
+      }
+    }
+
+    assert_eq!(stornos.len(), 1);
+    let invoice_store = service.invoice_store.lock().await;
+    assert_eq!(
+      invoice_store
+        .iter()
+        .filter(|i| i.unpack().kind == InvoiceKind::Storno)
+        .count(),
+      1
+    );
+    assert_eq!(
+      invoice_store
+        .find_id(&issued.id)
+        .unwrap()
+        .unpack()
+        .storno_id,
+      Some(Uuid::parse_str(&stornos[0].id).unwrap())
+    );
+  }
+
+  #[tokio::test]
+  async fn test_create_receipt_lost_response() {
+    let (service, mock) = test_service();
+    let form = ReceiptForm {
+      purchase_id: "p1".into(),
+      items: vec![invoice_form::Item {
+        name: "Metszőolló".into(),
+        quantity: 1,
+        unit: "db".into(),
+        price_unit_net: 1000,
+        vat: "27".into(),
+        total_price_net: 1000,
+        total_price_vat: 270,
+        total_price_gross: 1270,
+        ..invoice_form::Item::default()
+      }],
+      total_net: 1000,
+      total_vat: 270,
+      total_gross: 1270,
+      ..ReceiptForm::default()
+    };
+
+    // Receipt is issued, but its answer is lost,
+    // so it is sent again with the same call ID,
+    // and found by it
+    mock.set_mode(mock::MockMode::LostResponse);
+    let issued = service.create_receipt(form.clone()).await.unwrap();
+    assert_eq!(issued.receipt_id, mock.receipts()[0]);
+    let receipt = service.receipt_store.lock().await.as_vec()[0]
+      .unpack()
+      .clone();
+    assert_eq!(receipt.status, receipt::ReceiptStatus::Issued);
+    assert_eq!(receipt.receipt_id, Some(issued.receipt_id));
+
+    // The purchase has its receipt already
+    assert!(service.create_receipt(form).await.is_err());
+    assert_eq!(mock.receipts().len(), 1);
+    assert_eq!(service.receipt_store.lock().await.len(), 1);
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_register_payment_concurrently() {
+    let (service, mock) = test_service();
+    let service = Arc::new(service);
+    let issued = issued_invoice(&service, "p1").await;
+
+    let payments = (0..8)
+      .map(|_| {
+        let service = service.clone();
+        let form = PaymentForm {
+          id: issued.id.to_string(),
+          date: "2021-03-12T00:00:00Z".into(),
+          amount: 1270,
+          ..PaymentForm::default()
+        };
+        tokio::spawn(async move { service.register_payment(form).await })
+      })
+      .collect::<Vec<_>>();
+    let mut registered = 0;
+    for payment in payments {
+      if payment.await.unwrap().is_ok() {
+        registered += 1;
+      }
+    }
+
+    assert_eq!(registered, 1);
+    let invoice = service
+      .invoice_store
+      .lock()
+      .await
+      .find_id(&issued.id)
+      .unwrap()
+      .unpack()
+      .clone();
+    assert_eq!(invoice.payments.len(), 1);
+    assert_eq!(invoice.outstanding, Some(0));
+    assert_eq!(mock.invoices()[0].paid, 1270.0);
+  }
+
+  /// Next status of the subscription stream
+  async fn next_status(stream: &mut ReceiverStream<Result<InvoiceData, Status>>) -> i32 {
+    use tokio_stream::StreamExt;
+    tokio::time::timeout(Duration::from_secs(5), stream.next())
+      .await
+      .unwrap()
+      .unwrap()
+      .unwrap()
+      .status
+  }
+
+  #[tokio::test]
+  async fn test_subscribe_status() {
+    let (addr, _mock) = mock::start(([127, 0, 0, 1], 0).into()).unwrap();
+    let service = service_of(addr);
+    let created = service.create_new(invoice_form("p1")).await.unwrap();
+    let mut stream = service
+      .subscribe_status(SubscribeRequest {
+        id: created.id.clone(),
+      })
+      .await
+      .unwrap();
+
+    // Processor on the stores of the service
+    let processor = Arc::new(processor::InvoiceProcessor::new(
+      szamlazzhu::SzamlazzHu::mock(addr),
+      retry::RetryPolicy::default(),
+      service.rate_limiter.clone(),
+      service.processor_notify.clone(),
+      Duration::from_millis(50),
+      service.invoice_object_store.clone(),
+      service.invoice_store.clone(),
+      service.proforma_store.clone(),
+      service.status_tx.clone(),
+      service.webhook_log.clone(),
+      service.pdf_folder.clone(),
+    ));
+    tokio::spawn(processor.start(1));
+
+    let mut statuses = Vec::new();
+    while statuses.last() != Some(&(invoice_data::Status::Issued as i32)) {
+      statuses.push(next_status(&mut stream).await);
+    }
+    assert_eq!(
+      statuses,
+      vec![
+        invoice_data::Status::Queued as i32,
+        invoice_data::Status::Submitting as i32,
+        invoice_data::Status::Issued as i32
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_subscribe_status_lagged() {
+    let (service, _mock) = test_service();
+    let created = service.create_new(invoice_form("p1")).await.unwrap();
+    let id = Uuid::parse_str(&created.id).unwrap();
+    let mut stream = service
+      .subscribe_status(SubscribeRequest { id: created.id })
+      .await
+      .unwrap();
+    assert_eq!(
+      next_status(&mut stream).await,
+      invoice_data::Status::Queued as i32
+    );
+
+    // Its final change is pushed out by the changes of other invoices
+    let failed = {
+      let mut store = service.invoice_store.lock().await;
+      let mut i = store.find_id_mut(&id).unwrap().as_mut();
+      let i = i.unpack();
+      i.set_status(InvoiceStatus::Failed).unwrap();
+      i.clone()
+    };
+    service.status_tx.send(failed).unwrap();
+    for _ in 0..STATUS_CHANNEL_SIZE {
+      service.status_tx.send(invoice::Invoice::default()).unwrap();
+    }
+
+    assert_eq!(
+      next_status(&mut stream).await,
+      invoice_data::Status::Failed as i32
+    );
+  }
+
+  #[tokio::test]
+  async fn test_set_enqueue_failed() {
+    let (service, _mock) = test_service();
+    let mut status_rx = service.status_tx.subscribe();
+    let created = service.create_new(invoice_form("p1")).await.unwrap();
+    let id = Uuid::parse_str(&created.id).unwrap();
+    assert_eq!(
+      status_rx.recv().await.unwrap().status,
+      InvoiceStatus::Queued
+    );
+
+    service
+      .set_enqueue_failed(&service.invoice_store, &id)
+      .await
+      .unwrap();
+    let changed = status_rx.recv().await.unwrap();
+    assert_eq!((changed.id, changed.status), (id, InvoiceStatus::Failed));
+  }
+
+  #[tokio::test]
+  async fn test_retry_invoice_customer() {
+    let (service, _mock) = test_service();
+    let created = service.create_new(invoice_form("p1")).await.unwrap();
+    let id = Uuid::parse_str(&created.id).unwrap();
+    // Another invoice of the corrected tax number is indexed already
+    let mut other = invoice_form("p2");
+    other.customer.as_mut().unwrap().tax_number = "5678".into();
+    service.create_new(other).await.unwrap();
+    let found = |customer: &str| {
+      service.search_invoices(SearchRequest {
+        customer: customer.into(),
+        ..SearchRequest::default()
+      })
+    };
+    assert_eq!(found("1234").await.unwrap().invoices.len(), 1);
+    assert_eq!(found("5678").await.unwrap().invoices.len(), 1);
+
+    service
+      .invoice_store
+      .lock()
+      .await
+      .find_id_mut(&id)
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .set_status(InvoiceStatus::Failed)
+      .unwrap();
+    service
+      .retry_invoice(RetryRequest {
+        id: created.id,
+        customer: Some(invoice_form::Customer {
+          name: "Kert Bt.".into(),
+          tax_number: "5678".into(),
+          ..invoice_form::Customer::default()
+        }),
+      })
+      .await
+      .unwrap();
+
+    let invoice = service
+      .invoice_store
+      .lock()
+      .await
+      .find_id(&id)
+      .unwrap()
+      .unpack()
+      .clone();
+    assert_eq!(invoice.customer.tax_number, "5678");
+    assert!(found("1234").await.unwrap().invoices.is_empty());
+    assert_eq!(found("5678").await.unwrap().invoices.len(), 2);
+  }
 }
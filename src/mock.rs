@@ -0,0 +1,572 @@
+//! Local mock of the szamlazz.hu agent API
+//! Accepts the same multipart XML requests, and answers
+//! like szamlazz.hu, so the service can be tested offline.
+use chrono::{Datelike, Utc};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use quick_xml::de::from_str;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+// Content of the dummy PDFs
+const DUMMY_PDF: &str =
+  "%PDF-1.4\n1 0 obj << /Type /Catalog >> endobj\ntrailer << /Root 1 0 R >>\n%%EOF\n";
+
+/// Answer of the mock to the next requests
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum MockMode {
+  #[default]
+  Success,
+  // szamlazz.hu error code and message
+  Error(i32, String),
+  // Broken response body
+  Malformed,
+  // Next request is done, but its answer is lost
+  // Switches back to Success after it
+  LostResponse,
+}
+
+/// Invoice issued by the mock
+#[derive(Debug, Clone)]
+pub struct MockInvoice {
+  pub invoice_id: String,
+  pub order_number: Option<String>,
+  // szamlaKulsoAzon of the request
+  pub external_id: Option<String>,
+  // Invoice ID of the stornoed invoice
+  pub storno_of: Option<String>,
+  pub total_net: f64,
+  pub total_gross: f64,
+  pub paid: f64,
+}
+
+/// State of a running mock
+#[derive(Debug, Default)]
+pub struct MockState {
+  mode: Mutex<MockMode>,
+  invoices: Mutex<Vec<MockInvoice>>,
+  receipts: Mutex<Vec<String>>,
+  // Receipt numbers by the call IDs of the issued receipts
+  receipt_calls: Mutex<HashMap<String, String>>,
+}
+
+impl MockState {
+  pub fn set_mode(&self, mode: MockMode) {
+    *self.mode.lock().unwrap() = mode;
+  }
+  /// Invoices issued so far
+  pub fn invoices(&self) -> Vec<MockInvoice> {
+    self.invoices.lock().unwrap().clone()
+  }
+  /// Receipt numbers issued so far
+  pub fn receipts(&self) -> Vec<String> {
+    self.receipts.lock().unwrap().clone()
+  }
+  fn issue(
+    &self,
+    prefix: &str,
+    order_number: Option<String>,
+    external_id: Option<String>,
+    storno_of: Option<String>,
+    total_net: f64,
+    total_gross: f64,
+  ) -> MockInvoice {
+    let mut invoices = self.invoices.lock().unwrap();
+    let invoice = MockInvoice {
+      invoice_id: format!("{}-{}-{}", prefix, Utc::now().year(), invoices.len() + 1),
+      order_number,
+      external_id,
+      storno_of,
+      total_net,
+      total_gross,
+      paid: 0.0,
+    };
+    invoices.push(invoice.clone());
+    invoice
+  }
+  fn issue_receipt(&self, prefix: &str) -> String {
+    let mut receipts = self.receipts.lock().unwrap();
+    let receipt_id = format!("{}-{}-{}", prefix, Utc::now().year(), receipts.len() + 1);
+    receipts.push(receipt_id.clone());
+    receipt_id
+  }
+}
+
+/// Start the mock on the given address
+/// Port 0 binds a free port
+/// Returns the bound address, and the state to control the answers
+pub fn start(addr: SocketAddr) -> Result<(SocketAddr, Arc<MockState>), hyper::Error> {
+  let state = Arc::new(MockState::default());
+  let service_state = state.clone();
+  let make_service = make_service_fn(move |_| {
+    let state = service_state.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(handle(&state, req).await) }
+      }))
+    }
+  });
+  let server = Server::try_bind(&addr)?.serve(make_service);
+  let addr = server.local_addr();
+  tokio::spawn(async move {
+    if let Err(e) = server.await {
+      error!("Mock szamlazz.hu server error: {}", e);
+    }
+  });
+  Ok((addr, state))
+}
+
+async fn handle(state: &MockState, req: Request<Body>) -> Response<Body> {
+  let boundary = req
+    .headers()
+    .get("content-type")
+    .and_then(|c| c.to_str().ok())
+    .and_then(|c| c.split("boundary=").nth(1))
+    .map(|b| b.trim_matches('"').to_string());
+  let body = match hyper::body::to_bytes(req.into_body()).await {
+    Ok(body) => String::from_utf8_lossy(&body).to_string(),
+    Err(e) => return plain(StatusCode::BAD_REQUEST, &e.to_string()),
+  };
+  let (action, xml) = match boundary.and_then(|b| first_part(&body, &b)) {
+    Some(part) => part,
+    None => return plain(StatusCode::BAD_REQUEST, "Nem multipart kérés"),
+  };
+
+  let mode = state.mode.lock().unwrap().clone();
+  let is_receipt = action.starts_with("action-szamla_agent_nyugta");
+  match mode {
+    MockMode::Malformed => plain(StatusCode::OK, "<xmlszamlavalasz><sikeres>tru"),
+    MockMode::LostResponse => {
+      state.set_mode(MockMode::Success);
+      let _ = answer(state, &action, &xml);
+      plain(StatusCode::BAD_GATEWAY, "Bad Gateway")
+    }
+    MockMode::Error(code, message) if is_receipt => receipt_error(code, &message),
+    MockMode::Error(code, message) => invoice_error(code, &message),
+    MockMode::Success => match answer(state, &action, &xml) {
+      Ok(res) => res,
+      Err((code, message)) if is_receipt => receipt_error(code, &message),
+      Err((code, message)) => invoice_error(code, &message),
+    },
+  }
+}
+
+/// Successful answer of the given action
+/// Returns szamlazz.hu error code and message if the request is wrong
+fn answer(state: &MockState, action: &str, xml: &str) -> Result<Response<Body>, (i32, String)> {
+  let xml_error = |e: quick_xml::DeError| (57, format!("XML adat hiba: {}", e));
+  match action {
+    "action-xmlagentxmlfile" => {
+      let r: MockInvoiceRequest = from_str(xml).map_err(xml_error)?;
+      check_agent_key(&r.settings.agent_key)?;
+      if r.customer.name.trim().is_empty() {
+        return Err((57, "Hiányzó vevő név".to_string()));
+      }
+      if r.items.items.is_empty() {
+        return Err((57, "Hiányzó számla tételek".to_string()));
+      }
+      let duplicate = r.header.order_number.is_some()
+        && state
+          .invoices
+          .lock()
+          .unwrap()
+          .iter()
+          .any(|i| i.order_number == r.header.order_number);
+      if duplicate {
+        return Err((259, "A rendelésszám már szerepel egy számlán".to_string()));
+      }
+      let invoice = state.issue(
+        &r.header.invoice_prefix,
+        r.header.order_number,
+        r.settings.external_id,
+        None,
+        r.items.items.iter().map(|i| i.total_net).sum(),
+        r.items.items.iter().map(|i| i.total_gross).sum(),
+      );
+      Ok(invoice_response(&invoice, r.settings.response_version))
+    }
+    "action-szamla_agent_pdf" => {
+      let r: MockPdfRequest = from_str(xml).map_err(xml_error)?;
+      check_agent_key(&r.agent_key)?;
+      state
+        .invoices
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|i| {
+          (r.order_number.is_some() && i.order_number == r.order_number)
+            || (r.external_id.is_some() && i.external_id == r.external_id)
+        })
+        .map(|i| invoice_response(i, r.response_version))
+        .ok_or_else(|| (7, "Nem található a számla".to_string()))
+    }
+    "action-szamla_agent_st" => {
+      let r: MockStornoRequest = from_str(xml).map_err(xml_error)?;
+      check_agent_key(&r.settings.agent_key)?;
+      let original = state
+        .invoices
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|i| i.invoice_id == r.header.invoice_id)
+        .cloned()
+        .ok_or_else(|| (7, "Nem található a számla".to_string()))?;
+      let stornoed = state
+        .invoices
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|i| i.storno_of.as_ref() == Some(&original.invoice_id));
+      if stornoed {
+        return Err((335, "A számla már sztornózva van".to_string()));
+      }
+      let prefix = original.invoice_id.split('-').next().unwrap_or_default();
+      let storno = state.issue(
+        prefix,
+        None,
+        r.settings.external_id,
+        Some(original.invoice_id.clone()),
+        -original.total_net,
+        -original.total_gross,
+      );
+      Ok(invoice_response(&storno, r.settings.response_version))
+    }
+    "action-szamla_agent_kifiz" => {
+      let r: MockPaymentRequest = from_str(xml).map_err(xml_error)?;
+      check_agent_key(&r.settings.agent_key)?;
+      let mut invoices = state.invoices.lock().unwrap();
+      let invoice = invoices
+        .iter_mut()
+        .find(|i| i.invoice_id == r.settings.invoice_id)
+        .ok_or_else(|| (7, "Nem található a számla".to_string()))?;
+      invoice.paid += r.payment.amount;
+      Ok(invoice_response(invoice, r.settings.response_version))
+    }
+    "action-szamla_agent_nyugta_create" => {
+      let r: MockReceiptRequest = from_str(xml).map_err(xml_error)?;
+      check_agent_key(&r.settings.agent_key)?;
+      let mut receipt_calls = state.receipt_calls.lock().unwrap();
+      if let Some(call_id) = &r.header.call_id {
+        if receipt_calls.contains_key(call_id) {
+          return Err((336, "A hívásazonosító már szerepel egy nyugtán".to_string()));
+        }
+      }
+      let receipt_id = state.issue_receipt(&r.header.prefix);
+      if let Some(call_id) = r.header.call_id {
+        receipt_calls.insert(call_id, receipt_id.clone());
+      }
+      Ok(receipt_response(&receipt_id))
+    }
+    "action-szamla_agent_nyugta_storno" | "action-szamla_agent_nyugta_get" => {
+      let r: MockReceiptRequest = from_str(xml).map_err(xml_error)?;
+      check_agent_key(&r.settings.agent_key)?;
+      // Get finds it by its call ID too
+      let receipt_id = match (r.header.receipt_id, r.header.call_id) {
+        (None, Some(call_id)) if action == "action-szamla_agent_nyugta_get" => state
+          .receipt_calls
+          .lock()
+          .unwrap()
+          .get(&call_id)
+          .cloned()
+          .unwrap_or_default(),
+        (receipt_id, _) => receipt_id.unwrap_or_default(),
+      };
+      if !state.receipts.lock().unwrap().contains(&receipt_id) {
+        return Err((338, "Nem található nyugta".to_string()));
+      }
+      if action == "action-szamla_agent_nyugta_get" {
+        return Ok(receipt_response(&receipt_id));
+      }
+      let prefix = receipt_id.split('-').next().unwrap_or_default();
+      Ok(receipt_response(&state.issue_receipt(prefix)))
+    }
+    _ => Err((54, format!("Ismeretlen művelet: {}", action))),
+  }
+}
+
+/// Invoice object accepted by the mock
+#[cfg(test)]
+pub fn invoice_object() -> crate::invoice::InvoiceObject {
+  use crate::invoice::{Customer, InvoiceObject, Item, VAT};
+  InvoiceObject {
+    internal_id: uuid::Uuid::new_v4(),
+    cart_id: "p1".into(),
+    customer: Customer {
+      name: "Kert Kft.".into(),
+      ..Customer::default()
+    },
+    items: vec![Item::new(
+      "Metszőolló".into(),
+      1,
+      "db".into(),
+      1000,
+      VAT::_27,
+      1000,
+      270,
+      1270,
+    )
+    .unwrap()],
+    total_net: 1000,
+    total_vat: 270,
+    total_gross: 1270,
+    ..InvoiceObject::default()
+  }
+}
+
+fn check_agent_key(agent_key: &Option<String>) -> Result<(), (i32, String)> {
+  match agent_key {
+    Some(key) if !key.trim().is_empty() => Ok(()),
+    _ => Err((3, "Sikertelen bejelentkezés".to_string())),
+  }
+}
+
+/// Name and content of the first part of a multipart body
+fn first_part(body: &str, boundary: &str) -> Option<(String, String)> {
+  let part = body.split(&format!("--{}", boundary)).nth(1)?;
+  let (headers, content) = part.trim_start_matches("\r\n").split_once("\r\n\r\n")?;
+  let name = headers.split("name=\"").nth(1)?.split('"').next()?;
+  Some((
+    name.to_string(),
+    content.trim_end_matches("\r\n").to_string(),
+  ))
+}
+
+fn plain(status: StatusCode, body: &str) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .body(Body::from(body.to_string()))
+    .unwrap()
+}
+
+fn pdf_base64() -> String {
+  base64::encode(DUMMY_PDF)
+}
+
+/// Percent encode everything but the unreserved characters
+/// szamlazz.hu sends URLs and header values URL encoded
+fn url_encode(s: &str) -> String {
+  s.bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+        (b as char).to_string()
+      }
+      _ => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+/// Invoice answer by the requested response version
+/// Version 1 sends the invoice data in headers and the raw PDF as body,
+/// every other version the XML with base64 encoded PDF
+fn invoice_response(invoice: &MockInvoice, response_version: Option<u32>) -> Response<Body> {
+  let outstanding = (invoice.total_gross - invoice.paid).max(0.0);
+  let customer_url = url_encode(&format!(
+    "https://www.szamlazz.hu/szamla/?partner={}&id={}",
+    "mock", invoice.invoice_id
+  ));
+  if response_version == Some(1) {
+    return Response::builder()
+      .header("szlahu_szamlaszam", invoice.invoice_id.as_str())
+      .header("szlahu_nettovegosszeg", invoice.total_net.to_string())
+      .header("szlahu_bruttovegosszeg", invoice.total_gross.to_string())
+      .header("szlahu_kintlevoseg", outstanding.to_string())
+      .header("szlahu_vevoifiokurl", customer_url)
+      .header("Content-Type", "application/pdf")
+      .body(Body::from(DUMMY_PDF))
+      .unwrap();
+  }
+  let body = format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<xmlszamlavalasz xmlns="http://www.szamlazz.hu/xmlszamlavalasz">
+  <sikeres>true</sikeres>
+  <szamlaszam>{}</szamlaszam>
+  <szamlanetto>{}</szamlanetto>
+  <szamlabrutto>{}</szamlabrutto>
+  <kintlevoseg>{}</kintlevoseg>
+  <vevoifiokurl>{}</vevoifiokurl>
+  <pdf>{}</pdf>
+</xmlszamlavalasz>"#,
+    invoice.invoice_id,
+    invoice.total_net,
+    invoice.total_gross,
+    outstanding,
+    customer_url,
+    pdf_base64()
+  );
+  Response::builder()
+    .header("szlahu_szamlaszam", invoice.invoice_id.as_str())
+    .body(Body::from(body))
+    .unwrap()
+}
+
+/// szamlazz.hu reports invoice errors in the headers
+/// and in the response body
+fn invoice_error(code: i32, message: &str) -> Response<Body> {
+  let body = format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<xmlszamlavalasz xmlns="http://www.szamlazz.hu/xmlszamlavalasz">
+  <sikeres>false</sikeres>
+  <hibakod>{}</hibakod>
+  <hibauzenet>{}</hibauzenet>
+</xmlszamlavalasz>"#,
+    code, message
+  );
+  Response::builder()
+    .header("szlahu_error_code", code.to_string())
+    .header("szlahu_error", url_encode(message))
+    .body(Body::from(body))
+    .unwrap()
+}
+
+fn receipt_response(receipt_id: &str) -> Response<Body> {
+  let body = format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<xmlnyugtavalasz xmlns="http://www.szamlazz.hu/xmlnyugtavalasz">
+  <sikeres>true</sikeres>
+  <hibakod></hibakod>
+  <hibauzenet></hibauzenet>
+  <nyugtaPdf>{}</nyugtaPdf>
+  <nyugta>
+    <alap>
+      <nyugtaszam>{}</nyugtaszam>
+      <tipus>NY</tipus>
+      <stornozott>false</stornozott>
+    </alap>
+  </nyugta>
+</xmlnyugtavalasz>"#,
+    pdf_base64(),
+    receipt_id
+  );
+  plain(StatusCode::OK, &body)
+}
+
+/// szamlazz.hu reports receipt errors only in the response body
+fn receipt_error(code: i32, message: &str) -> Response<Body> {
+  let body = format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<xmlnyugtavalasz xmlns="http://www.szamlazz.hu/xmlnyugtavalasz">
+  <sikeres>false</sikeres>
+  <hibakod>{}</hibakod>
+  <hibauzenet>{}</hibauzenet>
+</xmlnyugtavalasz>"#,
+    code, message
+  );
+  plain(StatusCode::OK, &body)
+}
+
+// Request fields checked by the mock
+// Every other field is ignored
+
+#[derive(Debug, Deserialize)]
+struct MockSettings {
+  #[serde(rename = "szamlaagentkulcs")]
+  agent_key: Option<String>,
+  #[serde(rename = "szamlaszam", default)]
+  invoice_id: String,
+  #[serde(rename = "valaszVerzio")]
+  response_version: Option<u32>,
+  #[serde(rename = "szamlaKulsoAzon")]
+  external_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockInvoiceRequest {
+  #[serde(rename = "beallitasok")]
+  settings: MockSettings,
+  #[serde(rename = "fejlec")]
+  header: MockInvoiceHeader,
+  #[serde(rename = "vevo")]
+  customer: MockCustomer,
+  #[serde(rename = "tetelek")]
+  items: MockItems,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockInvoiceHeader {
+  #[serde(rename = "rendelesSzam")]
+  order_number: Option<String>,
+  #[serde(rename = "szamlaszamElotag")]
+  invoice_prefix: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockCustomer {
+  #[serde(rename = "nev")]
+  name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockItems {
+  #[serde(rename = "tetel", default)]
+  items: Vec<MockItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockItem {
+  #[serde(rename = "nettoErtek")]
+  total_net: f64,
+  #[serde(rename = "bruttoErtek")]
+  total_gross: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockPdfRequest {
+  #[serde(rename = "szamlaagentkulcs")]
+  agent_key: Option<String>,
+  #[serde(rename = "rendelesSzam")]
+  order_number: Option<String>,
+  #[serde(rename = "szamlaKulsoAzon")]
+  external_id: Option<String>,
+  #[serde(rename = "valaszVerzio")]
+  response_version: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockStornoRequest {
+  #[serde(rename = "beallitasok")]
+  settings: MockSettings,
+  #[serde(rename = "fejlec")]
+  header: MockStornoHeader,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockStornoHeader {
+  #[serde(rename = "szamlaszam")]
+  invoice_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockPaymentRequest {
+  #[serde(rename = "beallitasok")]
+  settings: MockSettings,
+  #[serde(rename = "kifizetes")]
+  payment: MockPayment,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockPayment {
+  #[serde(rename = "osszeg")]
+  amount: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockReceiptRequest {
+  #[serde(rename = "beallitasok")]
+  settings: MockSettings,
+  #[serde(rename = "fejlec")]
+  header: MockReceiptHeader,
+}
+
+#[derive(Debug, Deserialize)]
+struct MockReceiptHeader {
+  #[serde(rename = "elotag", default)]
+  prefix: String,
+  #[serde(rename = "nyugtaszam")]
+  receipt_id: Option<String>,
+  #[serde(rename = "hivasAzonosito")]
+  call_id: Option<String>,
+}
@@ -1,4 +1,7 @@
-use gzlib::proto::invoice::InvoiceData;
+use crate::proto::invoice::{
+  invoice_data, invoice_form, receipt_data, receivables_response, webhook_delivery, InvoiceData,
+  InvoiceDetails, ReceiptData, WebhookDelivery,
+};
 
 pub enum ServiceError {
   InternalError(String),
@@ -70,18 +73,292 @@ impl From<std::env::VarError> for ServiceError {
   }
 }
 
+impl From<crate::invoice::InvoiceError> for ServiceError {
+  fn from(error: crate::invoice::InvoiceError) -> Self {
+    ServiceError::bad_request(&error.to_string())
+  }
+}
+
+impl From<crate::invoice::AgentError> for ServiceError {
+  fn from(error: crate::invoice::AgentError) -> Self {
+    use crate::invoice::AgentError;
+    match error {
+      AgentError::DataError(_)
+      | AgentError::ProviderError(_, _)
+      | AgentError::InvalidTaxNumber(_, _) => ServiceError::bad_request(&error.to_string()),
+      AgentError::NotFound(_, _) => ServiceError::not_found(&error.to_string()),
+      AgentError::Duplicate(_, _) => ServiceError::already_exist(&error.to_string()),
+      _ => ServiceError::internal_error(&error.to_string()),
+    }
+  }
+}
+
+impl From<crate::receipt::ReceiptError> for ServiceError {
+  fn from(error: crate::receipt::ReceiptError) -> Self {
+    ServiceError::bad_request(&error.to_string())
+  }
+}
+
+impl From<crate::receipt::Receipt> for ReceiptData {
+  fn from(r: crate::receipt::Receipt) -> Self {
+    use crate::receipt::ReceiptStatus;
+    ReceiptData {
+      id: r.id.to_simple().to_string(),
+      purchase_id: r.purchase_id,
+      receipt_id: r.receipt_id.unwrap_or_default(),
+      status: match r.status {
+        ReceiptStatus::Submitting => receipt_data::Status::Submitting,
+        ReceiptStatus::Issued => receipt_data::Status::Issued,
+        ReceiptStatus::Failed => receipt_data::Status::Failed,
+        ReceiptStatus::Cancelled => receipt_data::Status::Cancelled,
+      } as i32,
+      failure: r.failure.map(|f| f.into()),
+      storno_receipt_id: r.storno_receipt_id.unwrap_or_default(),
+      total_net: r.total_net,
+      total_vat: r.total_vat,
+      total_gross: r.total_gross,
+      created_by: r.created_by,
+      created_at: r.created_at.to_rfc3339(),
+      cancelled_by: r.cancelled_by.unwrap_or_default(),
+      cancelled_at: r.cancelled_at.map(|d| d.to_rfc3339()).unwrap_or_default(),
+    }
+  }
+}
+
+impl From<crate::webhook::Delivery> for WebhookDelivery {
+  fn from(d: crate::webhook::Delivery) -> Self {
+    use crate::webhook::DeliveryStatus;
+    let to_rfc3339 =
+      |d: Option<chrono::DateTime<chrono::Utc>>| d.map(|d| d.to_rfc3339()).unwrap_or_default();
+    WebhookDelivery {
+      id: d.id.to_simple().to_string(),
+      endpoint: d.endpoint,
+      event: d.event.event,
+      invoice: d.event.id.to_simple().to_string(),
+      status: match d.status {
+        DeliveryStatus::Pending => webhook_delivery::Status::Pending,
+        DeliveryStatus::Delivered => webhook_delivery::Status::Delivered,
+        DeliveryStatus::Failed => webhook_delivery::Status::Failed,
+      } as i32,
+      attempt_count: d.attempt_count,
+      last_attempt_at: to_rfc3339(d.last_attempt_at),
+      next_attempt_at: to_rfc3339(d.next_attempt_at),
+      last_error: d.last_error.unwrap_or_default(),
+      delivered_at: to_rfc3339(d.delivered_at),
+    }
+  }
+}
+
+impl From<crate::report::Receivable> for receivables_response::Receivable {
+  fn from(r: crate::report::Receivable) -> Self {
+    receivables_response::Receivable {
+      id: r.invoice.id.to_simple().to_string(),
+      invoice_id: r.invoice.invoice_id.unwrap_or_default(),
+      purchase_id: r.invoice.purchase_id,
+      payment_duedate: r.payment_duedate.map(|d| d.to_string()).unwrap_or_default(),
+      outstanding: r.outstanding,
+      days_overdue: r.days_overdue as i32,
+    }
+  }
+}
+
+impl From<crate::report::CustomerReceivables> for receivables_response::Customer {
+  fn from(c: crate::report::CustomerReceivables) -> Self {
+    receivables_response::Customer {
+      name: c.customer_name,
+      tax_number: c.tax_number,
+      outstanding: c.outstanding,
+      overdue: c.overdue,
+      receivables: c.receivables.into_iter().map(|r| r.into()).collect(),
+    }
+  }
+}
+
+impl From<invoice_data::Status> for crate::invoice::InvoiceStatus {
+  fn from(s: invoice_data::Status) -> Self {
+    use crate::invoice::InvoiceStatus;
+    match s {
+      invoice_data::Status::Queued => InvoiceStatus::Queued,
+      invoice_data::Status::Submitting => InvoiceStatus::Submitting,
+      invoice_data::Status::Issued => InvoiceStatus::Issued,
+      invoice_data::Status::PdfMissing => InvoiceStatus::PdfMissing,
+      invoice_data::Status::Failed => InvoiceStatus::Failed,
+      invoice_data::Status::Cancelled => InvoiceStatus::Cancelled,
+      invoice_data::Status::Stornoed => InvoiceStatus::Stornoed,
+    }
+  }
+}
+
+impl From<crate::invoice::InvoiceStatus> for invoice_data::Status {
+  fn from(s: crate::invoice::InvoiceStatus) -> Self {
+    use crate::invoice::InvoiceStatus;
+    match s {
+      InvoiceStatus::Queued => invoice_data::Status::Queued,
+      InvoiceStatus::Submitting => invoice_data::Status::Submitting,
+      InvoiceStatus::Issued => invoice_data::Status::Issued,
+      InvoiceStatus::PdfMissing => invoice_data::Status::PdfMissing,
+      InvoiceStatus::Failed => invoice_data::Status::Failed,
+      InvoiceStatus::Cancelled => invoice_data::Status::Cancelled,
+      InvoiceStatus::Stornoed => invoice_data::Status::Stornoed,
+    }
+  }
+}
+
+impl From<crate::invoice::InvoiceKind> for invoice_data::Kind {
+  fn from(k: crate::invoice::InvoiceKind) -> Self {
+    use crate::invoice::InvoiceKind;
+    match k {
+      InvoiceKind::Normal => invoice_data::Kind::Normal,
+      InvoiceKind::Storno => invoice_data::Kind::Storno,
+      InvoiceKind::Corrective => invoice_data::Kind::Corrective,
+      InvoiceKind::Proforma => invoice_data::Kind::Proforma,
+      InvoiceKind::Deposit => invoice_data::Kind::Deposit,
+      InvoiceKind::Final => invoice_data::Kind::Final,
+    }
+  }
+}
+
+impl From<crate::invoice::PaymentMethod> for invoice_form::PaymentKind {
+  fn from(m: crate::invoice::PaymentMethod) -> Self {
+    use crate::invoice::PaymentMethod;
+    match m {
+      PaymentMethod::Cash => invoice_form::PaymentKind::Cash,
+      PaymentMethod::Transfer => invoice_form::PaymentKind::Transfer,
+      PaymentMethod::Card => invoice_form::PaymentKind::Card,
+    }
+  }
+}
+
+impl From<crate::invoice::Payment> for invoice_data::Payment {
+  fn from(p: crate::invoice::Payment) -> Self {
+    invoice_data::Payment {
+      date: p.date.to_string(),
+      amount: p.amount,
+      payment_kind: invoice_form::PaymentKind::from(p.payment_method) as i32,
+      created_by: p.created_by,
+      created_at: p.created_at.to_rfc3339(),
+    }
+  }
+}
+
+impl From<crate::invoice::StatusChange> for invoice_data::StatusChange {
+  fn from(s: crate::invoice::StatusChange) -> Self {
+    invoice_data::StatusChange {
+      status: invoice_data::Status::from(s.status) as i32,
+      created_at: s.created_at.to_rfc3339(),
+    }
+  }
+}
+
+impl From<crate::invoice::Failure> for invoice_data::Failure {
+  fn from(f: crate::invoice::Failure) -> Self {
+    use crate::invoice::FailureKind;
+    invoice_data::Failure {
+      kind: match f.kind {
+        FailureKind::DataError => invoice_data::failure::Kind::DataError,
+        FailureKind::InternalError => invoice_data::failure::Kind::InternalError,
+        FailureKind::ProviderError => invoice_data::failure::Kind::ProviderError,
+        FailureKind::ConnectionError => invoice_data::failure::Kind::ConnectionError,
+        FailureKind::PdfError => invoice_data::failure::Kind::PdfError,
+      } as i32,
+      message: f.message,
+      error_code: f.error_code.unwrap_or_default(),
+      created_at: f.created_at.to_rfc3339(),
+    }
+  }
+}
+
+impl From<crate::invoice::Customer> for invoice_form::Customer {
+  fn from(c: crate::invoice::Customer) -> Self {
+    invoice_form::Customer {
+      id: 0,
+      name: c.name,
+      tax_number: c.tax_number,
+      zip: c.zip,
+      location: c.location,
+      street: c.street,
+      email: String::default(),
+    }
+  }
+}
+
+impl From<crate::invoice::Item> for invoice_form::Item {
+  fn from(i: crate::invoice::Item) -> Self {
+    invoice_form::Item {
+      name: i.name,
+      quantity: i.quantity,
+      unit: i.unit,
+      price_unit_net: i.retail_price_net,
+      vat: i.vat.to_string(),
+      total_price_net: i.total_price_net,
+      total_price_vat: i.total_price_vat,
+      total_price_gross: i.total_price_gross,
+      comment: String::default(),
+    }
+  }
+}
+
+impl From<crate::invoice::Invoice> for InvoiceDetails {
+  fn from(i: crate::invoice::Invoice) -> Self {
+    InvoiceDetails {
+      customer: Some(i.customer.clone().into()),
+      items: i.items.iter().cloned().map(|i| i.into()).collect(),
+      payment_kind: invoice_form::PaymentKind::from(i.header.payment_method.clone()) as i32,
+      date: i.header.date_created.clone(),
+      completion_date: i.header.date_completion.clone(),
+      payment_duedate: i.header.payment_duedate.clone(),
+      total_net: i.total_net,
+      total_vat: i.total_vat,
+      total_gross: i.total_gross,
+      invoice: Some(i.into()),
+    }
+  }
+}
+
 impl From<crate::invoice::Invoice> for InvoiceData {
   fn from(f: crate::invoice::Invoice) -> Self {
     InvoiceData {
+      is_paid: f.is_paid(),
       id: f.id.to_simple().to_string(),
+      has_error: f.status == crate::invoice::InvoiceStatus::Failed,
       purchase_id: f.purchase_id,
-      invoice_id: match f.invoice_id {
-        Some(iid) => iid,
-        None => String::default(),
-      },
-      has_error: f.has_error,
+      invoice_id: f.invoice_id.unwrap_or_default(),
       created_by: f.created_by,
       created_at: f.created_at.to_rfc3339(),
+      status: invoice_data::Status::from(f.status) as i32,
+      status_history: f.status_history.into_iter().map(|s| s.into()).collect(),
+      failure: f.failure.map(|f| f.into()),
+      attempt_count: f.attempt_count,
+      last_attempt_at: f
+        .last_attempt_at
+        .map(|d| d.to_rfc3339())
+        .unwrap_or_default(),
+      next_attempt_at: f
+        .next_attempt_at
+        .map(|d| d.to_rfc3339())
+        .unwrap_or_default(),
+      kind: invoice_data::Kind::from(f.kind) as i32,
+      reference_id: f
+        .reference_id
+        .map(|id| id.to_simple().to_string())
+        .unwrap_or_default(),
+      storno_id: f
+        .storno_id
+        .map(|id| id.to_simple().to_string())
+        .unwrap_or_default(),
+      storno_invoice_id: f.storno_invoice_id.unwrap_or_default(),
+      final_id: f
+        .final_id
+        .map(|id| id.to_simple().to_string())
+        .unwrap_or_default(),
+      deposit_ids: f
+        .deposit_ids
+        .iter()
+        .map(|id| id.to_simple().to_string())
+        .collect(),
+      outstanding: f.outstanding.unwrap_or_default(),
+      payments: f.payments.into_iter().map(|p| p.into()).collect(),
+      customer_url: f.customer_url.unwrap_or_default(),
     }
   }
 }
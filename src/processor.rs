@@ -0,0 +1,921 @@
+use crate::invoice::{self, InvoiceKind, InvoiceStatus};
+use crate::{file, rate_limit::RateLimiter, retry::RetryPolicy, webhook};
+use chrono::{DateTime, Utc};
+use packman::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+use uuid::Uuid;
+
+// Queue size of a single worker
+const WORKER_QUEUE_SIZE: usize = 100;
+
+/// Status changes of the invoices
+/// Subscribers receive the invoice after each change
+pub type StatusSender = broadcast::Sender<invoice::Invoice>;
+
+pub struct InvoiceProcessor<T>
+where
+  T: invoice::InvoiceAgent + Send + Sync + 'static,
+{
+  agent: T,
+  retry_policy: RetryPolicy,
+  // Shared with the receipt requests
+  rate_limiter: Arc<RateLimiter>,
+  // Notified when there is a new invoice object to process
+  notify: Arc<Notify>,
+  // Check the invoice object store at least this often
+  poll_interval: Duration,
+  invoice_objects: Arc<Mutex<VecPack<invoice::InvoiceObject>>>,
+  invoices: Arc<Mutex<VecPack<invoice::Invoice>>>,
+  proformas: Arc<Mutex<VecPack<invoice::Invoice>>>,
+  // Publish the status changes of the processed invoices
+  status_tx: StatusSender,
+  // Webhook deliveries of the status changes
+  delivery_log: Arc<webhook::DeliveryLog>,
+  // Folder of the invoice PDFs
+  pdf_folder: PathBuf,
+}
+
+impl<T> InvoiceProcessor<T>
+where
+  T: invoice::InvoiceAgent + Send + Sync + 'static,
+{
+  pub fn new(
+    agent: T,
+    retry_policy: RetryPolicy,
+    rate_limiter: Arc<RateLimiter>,
+    notify: Arc<Notify>,
+    poll_interval: Duration,
+    invoice_objects: Arc<Mutex<VecPack<invoice::InvoiceObject>>>,
+    invoices: Arc<Mutex<VecPack<invoice::Invoice>>>,
+    proformas: Arc<Mutex<VecPack<invoice::Invoice>>>,
+    status_tx: StatusSender,
+    delivery_log: Arc<webhook::DeliveryLog>,
+    pdf_folder: PathBuf,
+  ) -> Self {
+    InvoiceProcessor {
+      agent,
+      retry_policy,
+      rate_limiter,
+      notify,
+      poll_interval,
+      invoice_objects,
+      invoices,
+      proformas,
+      status_tx,
+      delivery_log,
+      pdf_folder,
+    }
+  }
+
+  /// Start the given number of workers,
+  /// and dispatch the due invoice objects from the invoice object store
+  /// between them. Invoice objects of the same purchase always go
+  /// to the same worker, so they are processed in order.
+  /// Returns only if a worker is closed, so it can be restarted.
+  pub async fn start(self: Arc<Self>, worker_count: usize) {
+    // Invoice objects under processing
+    let in_progress: Arc<Mutex<HashSet<Uuid>>> = Arc::new(Mutex::new(HashSet::new()));
+
+    // Start workers
+    let (workers, worker_handles): (Vec<_>, Vec<_>) = (0..worker_count.max(1))
+      .map(|worker_id| {
+        let (tx, mut rx) = mpsc::channel::<invoice::InvoiceObject>(WORKER_QUEUE_SIZE);
+        let processor = self.clone();
+        let in_progress = in_progress.clone();
+        let handle = tokio::spawn(async move {
+          while let Some(invoice_object) = rx.recv().await {
+            let inner_id = invoice_object.internal_id;
+            processor.process(invoice_object).await;
+            in_progress.lock().await.remove(&inner_id);
+          }
+          error!("Invoice worker closed: {}", worker_id);
+        });
+        (tx, handle)
+      })
+      .unzip();
+
+    // Resolves as soon as any worker ends, even by a panic
+    let mut closed_worker = futures::future::select_all(worker_handles);
+
+    // Do the processes
+    // Infinite loop till the workers are alive
+    let other_workers = loop {
+      for invoice_object in self.due_invoice_objects(&in_progress).await {
+        in_progress.lock().await.insert(invoice_object.internal_id);
+        let worker = &workers[worker_index(&invoice_object.cart_id, workers.len())];
+        if let Err(e) = worker.send(invoice_object).await {
+          // Its worker is closed, so the watch below returns
+          error!("Cannot send invoice object to worker: {}", e.0.internal_id);
+          break;
+        }
+      }
+
+      // Wait for new invoice objects, or the next poll
+      tokio::select! {
+        biased;
+        (res, worker_id, other_workers) = &mut closed_worker => {
+          if let Err(e) = res {
+            error!("Invoice worker failed: {}; {}", worker_id, e);
+          }
+          break other_workers;
+        }
+        _ = tokio::time::timeout(self.poll_interval, self.notify.notified()) => (),
+      }
+    };
+
+    // Stop the other workers, and wait till they finish
+    // their current invoice, so a restart cannot submit it again.
+    drop(workers);
+    for handle in other_workers {
+      let _ = handle.await;
+    }
+    error!("Background process closed!");
+  }
+
+  /// Invoice objects ready to be processed, in order of their creation.
+  /// Queued ones whose retry time has come,
+  /// and in flight ones left by a previous run or waiting for a lookup.
+  async fn due_invoice_objects(
+    &self,
+    in_progress: &Mutex<HashSet<Uuid>>,
+  ) -> Vec<invoice::InvoiceObject> {
+    // Only the invoice objects are scanned, as they are
+    // the in flight ones; the invoice stores grow without bound
+    let candidates = {
+      let in_progress = in_progress.lock().await;
+      self
+        .invoice_objects
+        .lock()
+        .await
+        .iter()
+        .filter(|o| !in_progress.contains(&o.unpack().internal_id))
+        .map(|o| o.unpack().clone())
+        .collect::<Vec<invoice::InvoiceObject>>()
+    };
+
+    let now = Utc::now();
+    let mut res = Vec::new();
+    for o in candidates {
+      let store = match o.kind {
+        InvoiceKind::Proforma => &self.proformas,
+        _ => &self.invoices,
+      };
+      let due = store
+        .lock()
+        .await
+        .find_id(&o.internal_id)
+        .map(|i| match i.unpack().status {
+          InvoiceStatus::Queued | InvoiceStatus::Submitting | InvoiceStatus::PdfMissing => i
+            .unpack()
+            .next_attempt_at
+            .map(|next| next <= now)
+            .unwrap_or(true),
+          _ => false,
+        })
+        .unwrap_or(false);
+      if due {
+        res.push(o);
+      }
+    }
+    res.sort_by_key(|o| o.created_at);
+    res
+  }
+
+  async fn process(&self, invoice_object: invoice::InvoiceObject) {
+    let inner_id = invoice_object.internal_id;
+
+    // Invoice is in flight, when the process was stopped
+    // during its submission. It might be issued already,
+    // so we need to check it before submitting it again.
+    // The same is true for a retry after a connection error,
+    // as the lost request might have reached the agent.
+    let (status, lost_request) = self
+      .store_of(&inner_id)
+      .await
+      .lock()
+      .await
+      .find_id(&inner_id)
+      .map(|i| {
+        (
+          i.unpack().status.clone(),
+          i.unpack()
+            .failure
+            .as_ref()
+            .is_some_and(|f| f.kind == invoice::FailureKind::ConnectionError),
+        )
+      })
+      .unwrap_or_default();
+
+    let result = match status {
+      // Invoice is issued, only its PDF needs to be saved
+      InvoiceStatus::PdfMissing => self.issued_summary(&invoice_object).await,
+      _ => {
+        // Set submitting status
+        // If it cannot be set (e.g. it is cancelled meanwhile), do not submit it
+        if status != InvoiceStatus::Submitting
+          && !self
+            .update_invoice(&inner_id, |i| i.set_status(InvoiceStatus::Submitting))
+            .await
+        {
+          return;
+        }
+
+        let issued = if status == InvoiceStatus::Submitting || lost_request {
+          match self.lookup(&invoice_object).await {
+            Ok(issued) => issued,
+            Err(e) => {
+              // We cannot tell if it is issued, so it stays in flight
+              // till the agent can answer it
+              error!("In flight invoice lookup error: {}; {}", inner_id, e);
+              self
+                .update_invoice(&inner_id, |i| {
+                  let next_attempt_at = self.next_attempt_at(i.attempt_count);
+                  i.set_unresolved((&e).into(), next_attempt_at)
+                })
+                .await;
+              return;
+            }
+          }
+        } else {
+          None
+        };
+
+        match issued {
+          Some(invoice_summary) => {
+            info!(
+              "In flight invoice is already issued: {}; {}",
+              inner_id, invoice_summary.invoice_id
+            );
+            Ok(invoice_summary)
+          }
+          None => {
+            self.rate_limiter.wait().await;
+            self.submit(invoice_object.clone()).await
+          }
+        }
+      }
+    };
+
+    match result {
+      Ok(invoice_summary) => {
+        let invoice_id = invoice_summary.invoice_id.clone();
+        self.save_issued(&invoice_object, invoice_summary).await;
+        // Storno is linked when it is issued,
+        // with or without its PDF
+        if invoice_object.kind == InvoiceKind::Storno && status != InvoiceStatus::PdfMissing {
+          self.link_storno(&invoice_object, invoice_id).await;
+        }
+      }
+      Err(e) if status == InvoiceStatus::PdfMissing => {
+        error!("Invoice PDF download error: {}; {}", inner_id, e);
+        let next_attempt_at = self.next_pdf_attempt_at();
+        self
+          .update_invoice(&inner_id, |i| {
+            i.set_pdf_missing((&e).into(), next_attempt_at)
+          })
+          .await;
+      }
+      Err(e) => {
+        error!("Invoice creation error: {}; {}", inner_id, e);
+
+        let attempt_count = self
+          .store_of(&inner_id)
+          .await
+          .lock()
+          .await
+          .find_id(&inner_id)
+          .map(|i| i.unpack().attempt_count)
+          .unwrap_or_default();
+
+        if e.is_retryable() && self.retry_policy.can_retry(attempt_count) {
+          // Queue it again with backoff,
+          // and keep its InvoiceObject
+          let next_attempt_at = self.next_attempt_at(attempt_count);
+          self
+            .update_invoice(&inner_id, |i| i.set_retry((&e).into(), next_attempt_at))
+            .await;
+        } else {
+          // Set error occured with its reason
+          // InvoiceObject is kept, so it can be retried later
+          self
+            .update_invoice(&inner_id, |i| i.set_failed((&e).into()))
+            .await;
+        }
+      }
+    }
+  }
+
+  /// Submit invoice object to the invoice agent by its kind
+  async fn submit(
+    &self,
+    invoice_object: invoice::InvoiceObject,
+  ) -> Result<invoice::InvoiceSummary, invoice::AgentError> {
+    match invoice_object.kind {
+      InvoiceKind::Normal
+      | InvoiceKind::Corrective
+      | InvoiceKind::Proforma
+      | InvoiceKind::Deposit
+      | InvoiceKind::Final => {
+        let order_number = invoice_object.order_number();
+        match self.agent.create_invoice(invoice_object).await {
+          // Issued by a previous attempt whose response was lost
+          Err(invoice::AgentError::Duplicate(_, _)) => {
+            self.rate_limiter.wait().await;
+            self
+              .agent
+              .find_invoice(&order_number)
+              .await?
+              .ok_or_else(|| {
+                invoice::AgentError::InternalError(
+                  "Duplicate invoice not found by the agent".to_string(),
+                )
+              })
+          }
+          res => res,
+        }
+      }
+      InvoiceKind::Storno => self.agent.cancel_invoice(invoice_object).await,
+    }
+  }
+
+  /// Set the referenced invoice stornoed by the issued storno invoice
+  async fn link_storno(&self, invoice_object: &invoice::InvoiceObject, storno_invoice_id: String) {
+    let reference_id = match invoice_object.reference_id {
+      Some(id) => id,
+      None => {
+        error!(
+          "Storno invoice without reference: {}",
+          invoice_object.internal_id
+        );
+        return;
+      }
+    };
+    self
+      .update_invoice(&reference_id, |i| {
+        i.set_status(InvoiceStatus::Stornoed)?;
+        i.storno_id = Some(invoice_object.internal_id);
+        i.storno_invoice_id = Some(storno_invoice_id);
+        Ok(())
+      })
+      .await;
+  }
+
+  /// Save the PDF of an issued invoice, and set its status.
+  /// If the PDF cannot be saved, the invoice object is kept
+  /// with its PDF payload, so saving can be retried later.
+  async fn save_issued(
+    &self,
+    invoice_object: &invoice::InvoiceObject,
+    invoice_summary: invoice::InvoiceSummary,
+  ) {
+    let inner_id = &invoice_object.internal_id;
+    let saved = match &invoice_summary.pdf {
+      Some(pdf) => file::save_invoice_pdf(&self.pdf_folder, &invoice_summary.invoice_id, pdf).await,
+      None => Err(file::FileError::DecodeError),
+    };
+    match saved {
+      Ok(_) => {
+        // Set InvoiceID and issued status
+        let invoice_id = invoice_summary.invoice_id;
+        let outstanding = invoice_summary.outstanding;
+        let customer_url = invoice_summary.customer_url;
+        self
+          .update_invoice(inner_id, |i| {
+            i.invoice_id = Some(invoice_id);
+            i.outstanding = outstanding.or(i.outstanding);
+            i.customer_url = customer_url.or_else(|| i.customer_url.take());
+            i.set_status(InvoiceStatus::Issued)
+          })
+          .await;
+
+        // And remove InvoiceObject
+        if let Err(e) = self.invoice_objects.lock().await.remove_pack(inner_id) {
+          error!(
+            "Error while removing invoice object from storage: {}; {}",
+            inner_id, e
+          );
+        }
+      }
+      Err(e) => {
+        error!(
+          "Invoice PDF SAVE ERROR: {}; {}",
+          invoice_summary.invoice_id, e
+        );
+
+        // Keep the PDF for the next try,
+        // unless it is broken
+        let pdf = invoice_summary.pdf;
+        match self.invoice_objects.lock().await.find_id_mut(inner_id) {
+          Ok(o) => o.as_mut().unpack().pdf = pdf,
+          Err(e) => error!("Invoice object not found: {}; {}", inner_id, e),
+        }
+
+        let invoice_id = invoice_summary.invoice_id;
+        let outstanding = invoice_summary.outstanding;
+        let customer_url = invoice_summary.customer_url;
+        let next_attempt_at = self.next_pdf_attempt_at();
+        self
+          .update_invoice(inner_id, |i| {
+            i.invoice_id = Some(invoice_id);
+            i.outstanding = outstanding.or(i.outstanding);
+            i.customer_url = customer_url.or_else(|| i.customer_url.take());
+            i.set_pdf_missing(invoice::Failure::pdf_error(&e), next_attempt_at)
+          })
+          .await;
+      }
+    }
+  }
+
+  /// Next attempt with backoff after the given number of attempts
+  fn next_attempt_at(&self, attempt_count: u32) -> DateTime<Utc> {
+    Utc::now()
+      + chrono::Duration::from_std(self.retry_policy.delay(attempt_count))
+        .unwrap_or_else(|_| chrono::Duration::zero())
+  }
+
+  /// Next time to try saving a missing PDF
+  fn next_pdf_attempt_at(&self) -> DateTime<Utc> {
+    Utc::now()
+      + chrono::Duration::from_std(self.retry_policy.base_delay)
+        .unwrap_or_else(|_| chrono::Duration::zero())
+  }
+
+  /// Summary of an already issued invoice whose PDF is missing.
+  /// Uses the kept PDF payload if there is any,
+  /// otherwise downloads it again from the invoice agent.
+  async fn issued_summary(
+    &self,
+    invoice_object: &invoice::InvoiceObject,
+  ) -> Result<invoice::InvoiceSummary, invoice::AgentError> {
+    let invoice_id = self
+      .store_of(&invoice_object.internal_id)
+      .await
+      .lock()
+      .await
+      .find_id(&invoice_object.internal_id)
+      .ok()
+      .and_then(|i| i.unpack().invoice_id.clone());
+
+    if let (Some(invoice_id), Some(pdf)) = (invoice_id, &invoice_object.pdf) {
+      return Ok(invoice::InvoiceSummary {
+        invoice_id,
+        pdf: Some(pdf.clone()),
+        outstanding: None,
+        customer_url: None,
+      });
+    }
+
+    self.lookup(invoice_object).await?.ok_or_else(|| {
+      invoice::AgentError::InternalError("Issued invoice not found by the agent".to_string())
+    })
+  }
+
+  /// Already issued invoice of an in flight invoice object
+  /// None if the agent does not know it, so it can be submitted.
+  async fn lookup(
+    &self,
+    invoice_object: &invoice::InvoiceObject,
+  ) -> Result<Option<invoice::InvoiceSummary>, invoice::AgentError> {
+    let order_number = invoice_object.order_number();
+    self.rate_limiter.wait().await;
+    match invoice_object.kind {
+      InvoiceKind::Storno => self.agent.find_storno(&order_number).await,
+      _ => self.agent.find_invoice(&order_number).await,
+    }
+  }
+
+  /// Store of the given invoice
+  /// Proformas are stored separately from the invoices
+  async fn store_of(&self, id: &Uuid) -> &Mutex<VecPack<invoice::Invoice>> {
+    if self.proformas.lock().await.find_id(id).is_ok() {
+      &self.proformas
+    } else {
+      &self.invoices
+    }
+  }
+
+  /// Update invoice in the invoice store,
+  /// record its webhook deliveries,
+  /// and publish its new status to the subscribers.
+  /// Only logs the error, as the processor must keep running.
+  /// Returns true if the update succeeded.
+  async fn update_invoice<F>(&self, id: &Uuid, f: F) -> bool
+  where
+    F: FnOnce(&mut invoice::Invoice) -> Result<(), invoice::InvoiceError>,
+  {
+    let (previous, updated) = match self.store_of(id).await.lock().await.find_id_mut(id) {
+      Ok(i) => {
+        let mut i = i.as_mut();
+        let invoice = i.unpack();
+        let previous = invoice.status.clone();
+        match f(invoice) {
+          Ok(_) => (previous, invoice.clone()),
+          Err(e) => {
+            error!("Invoice update error: {}; {}", id, e);
+            return false;
+          }
+        }
+      }
+      Err(e) => {
+        error!("Invoice not found in store: {}; {}", id, e);
+        return false;
+      }
+    };
+    self.delivery_log.record(&previous, &updated).await;
+    // Error only if there is no subscriber
+    let _ = self.status_tx.send(updated);
+    true
+  }
+}
+
+/// Worker index for the given purchase ID
+fn worker_index(purchase_id: &str, worker_count: usize) -> usize {
+  let mut hasher = DefaultHasher::new();
+  purchase_id.hash(&mut hasher);
+  (hasher.finish() % worker_count as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mock::{self, MockMode, MockState};
+  use crate::szamlazzhu::SzamlazzHu;
+
+  const WEBHOOK_ENDPOINT: &str = "http://localhost/hook";
+
+  /// Processor of the given agent on empty stores of a temp folder
+  fn processor_of<A>(agent: A) -> Arc<InvoiceProcessor<A>>
+  where
+    A: invoice::InvoiceAgent + Send + Sync + 'static,
+  {
+    let dir = std::env::temp_dir().join(format!("invoice_processor_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(dir.join(crate::PDF_FOLDER_NAME)).unwrap();
+    let (status_tx, _) = broadcast::channel(10);
+    Arc::new(InvoiceProcessor::new(
+      agent,
+      RetryPolicy::default(),
+      Arc::new(RateLimiter::new(Duration::from_millis(0))),
+      Arc::new(Notify::new()),
+      Duration::from_millis(50),
+      Arc::new(Mutex::new(
+        VecPack::load_or_init(dir.join("invoice_objects")).unwrap(),
+      )),
+      Arc::new(Mutex::new(
+        VecPack::load_or_init(dir.join("invoices")).unwrap(),
+      )),
+      Arc::new(Mutex::new(
+        VecPack::load_or_init(dir.join("proformas")).unwrap(),
+      )),
+      status_tx,
+      Arc::new(webhook::DeliveryLog::new(
+        vec![WEBHOOK_ENDPOINT.to_string()],
+        Arc::new(Mutex::new(
+          VecPack::load_or_init(dir.join("webhook_deliveries")).unwrap(),
+        )),
+        Arc::new(Notify::new()),
+      )),
+      dir.join(crate::PDF_FOLDER_NAME),
+    ))
+  }
+
+  /// Processor with its agent pointed to a local mock server
+  fn test_processor() -> (Arc<InvoiceProcessor<SzamlazzHu>>, Arc<MockState>) {
+    let (addr, mock) = mock::start(([127, 0, 0, 1], 0).into()).unwrap();
+    (processor_of(SzamlazzHu::mock(addr)), mock)
+  }
+
+  /// Store the invoice object as the service does
+  async fn enqueue(
+    processor: &InvoiceProcessor<impl invoice::InvoiceAgent + Send + Sync + 'static>,
+    invoice_object: &invoice::InvoiceObject,
+  ) {
+    processor
+      .invoices
+      .lock()
+      .await
+      .insert(invoice_object.clone().into())
+      .unwrap();
+    processor
+      .invoice_objects
+      .lock()
+      .await
+      .insert(invoice_object.clone())
+      .unwrap();
+    processor.notify.notify_one();
+  }
+
+  /// Invoice object left in flight by a stopped process
+  async fn in_flight(
+    processor: &InvoiceProcessor<SzamlazzHu>,
+    invoice_object: &invoice::InvoiceObject,
+  ) {
+    enqueue(processor, invoice_object).await;
+    processor
+      .invoices
+      .lock()
+      .await
+      .find_id_mut(&invoice_object.internal_id)
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .set_status(InvoiceStatus::Submitting)
+      .unwrap();
+  }
+
+  /// Wait till the running processor sets the given status
+  async fn wait_for(
+    processor: &InvoiceProcessor<SzamlazzHu>,
+    id: &Uuid,
+    status: InvoiceStatus,
+  ) -> invoice::Invoice {
+    for _ in 0..100 {
+      let i = invoice(processor, id).await;
+      if i.status == status {
+        return i;
+      }
+      tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Invoice {} is not {:?}", id, status);
+  }
+
+  async fn invoice(processor: &InvoiceProcessor<SzamlazzHu>, id: &Uuid) -> invoice::Invoice {
+    processor
+      .invoices
+      .lock()
+      .await
+      .find_id(id)
+      .unwrap()
+      .unpack()
+      .clone()
+  }
+
+  #[tokio::test]
+  async fn test_reconcile_in_flight() {
+    use invoice::InvoiceAgent;
+    let (processor, mock) = test_processor();
+
+    // Issued before the process was stopped
+    let issued = mock::invoice_object();
+    in_flight(&processor, &issued).await;
+    let summary = processor
+      .agent
+      .create_invoice(issued.clone())
+      .await
+      .unwrap();
+    processor.process(issued.clone()).await;
+    let i = invoice(&processor, &issued.internal_id).await;
+    assert_eq!(i.status, InvoiceStatus::Issued);
+    assert_eq!(i.invoice_id, Some(summary.invoice_id));
+    assert_eq!(mock.invoices().len(), 1);
+
+    // Not issued yet
+    let not_issued = mock::invoice_object();
+    in_flight(&processor, &not_issued).await;
+    processor.process(not_issued.clone()).await;
+    let i = invoice(&processor, &not_issued.internal_id).await;
+    assert_eq!(i.status, InvoiceStatus::Issued);
+    assert_eq!(mock.invoices().len(), 2);
+    assert!(processor
+      .invoice_objects
+      .lock()
+      .await
+      .find_id(&not_issued.internal_id)
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_reconcile_in_flight_storno() {
+    use invoice::InvoiceAgent;
+    let (processor, mock) = test_processor();
+    let invoice_object = mock::invoice_object();
+    enqueue(&processor, &invoice_object).await;
+    processor.process(invoice_object.clone()).await;
+    let issued = invoice(&processor, &invoice_object.internal_id).await;
+
+    // Storno issued before the process was stopped
+    let storno_object =
+      invoice::InvoiceObject::new_storno(&issued, Utc::now().naive_utc().date(), 1);
+    in_flight(&processor, &storno_object).await;
+    let summary = processor
+      .agent
+      .cancel_invoice(storno_object.clone())
+      .await
+      .unwrap();
+    processor.process(storno_object.clone()).await;
+    let storno = invoice(&processor, &storno_object.internal_id).await;
+    assert_eq!(storno.status, InvoiceStatus::Issued);
+    assert_eq!(storno.invoice_id, Some(summary.invoice_id));
+    assert_eq!(
+      invoice(&processor, &issued.id).await.status,
+      InvoiceStatus::Stornoed
+    );
+    assert_eq!(mock.invoices().len(), 2);
+
+    // szamlazz.hu refuses to storno it again
+    match processor.agent.cancel_invoice(storno_object).await {
+      Err(invoice::AgentError::ProviderError(code, _)) => assert_eq!(code, 335),
+      r => panic!("Unexpected result: {:?}", r.map(|s| s.invoice_id)),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_pdf_missing() {
+    use invoice::InvoiceAgent;
+    let (processor, _mock) = test_processor();
+    let invoice_object = mock::invoice_object();
+    enqueue(&processor, &invoice_object).await;
+    processor.process(invoice_object.clone()).await;
+    let issued = invoice(&processor, &invoice_object.internal_id).await;
+
+    // Issued, but its PDF was broken
+    let storno_object =
+      invoice::InvoiceObject::new_storno(&issued, Utc::now().naive_utc().date(), 1);
+    in_flight(&processor, &storno_object).await;
+    let summary = processor
+      .agent
+      .cancel_invoice(storno_object.clone())
+      .await
+      .unwrap();
+    processor
+      .invoices
+      .lock()
+      .await
+      .find_id_mut(&storno_object.internal_id)
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .set_pdf_missing(
+        invoice::Failure::pdf_error(&file::FileError::DecodeError),
+        Utc::now(),
+      )
+      .unwrap();
+
+    // PDF is downloaded again by the storno lookup
+    processor.process(storno_object.clone()).await;
+    let storno = invoice(&processor, &storno_object.internal_id).await;
+    assert_eq!(storno.status, InvoiceStatus::Issued);
+    assert_eq!(storno.invoice_id, Some(summary.invoice_id));
+    assert!(processor.invoice_objects.lock().await.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_reconcile_lookup_error() {
+    let (processor, mock) = test_processor();
+    let invoice_object = mock::invoice_object();
+    in_flight(&processor, &invoice_object).await;
+
+    // Cannot tell if it is issued, so it stays in flight
+    mock.set_mode(MockMode::Error(999, "Ismeretlen hiba".into()));
+    processor.process(invoice_object.clone()).await;
+    let i = invoice(&processor, &invoice_object.internal_id).await;
+    assert_eq!(i.status, InvoiceStatus::Submitting);
+    assert_eq!(i.failure.unwrap().error_code, Some(999));
+    assert!(i.next_attempt_at.is_some());
+    assert!(processor
+      .due_invoice_objects(&Mutex::new(HashSet::new()))
+      .await
+      .is_empty());
+
+    mock.set_mode(MockMode::Success);
+    processor.process(invoice_object.clone()).await;
+    let i = invoice(&processor, &invoice_object.internal_id).await;
+    assert_eq!(i.status, InvoiceStatus::Issued);
+    assert_eq!(mock.invoices().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_invoice_lifecycle() {
+    let (processor, mock) = test_processor();
+    tokio::spawn(processor.clone().start(2));
+
+    let invoice_object = mock::invoice_object();
+    enqueue(&processor, &invoice_object).await;
+    let issued = wait_for(
+      &processor,
+      &invoice_object.internal_id,
+      InvoiceStatus::Issued,
+    )
+    .await;
+    assert!(issued.invoice_id.is_some());
+    assert_eq!(issued.attempt_count, 1);
+
+    // Storno is enqueued as the service does
+    let storno_object =
+      invoice::InvoiceObject::new_storno(&issued, Utc::now().naive_utc().date(), 1);
+    processor
+      .invoices
+      .lock()
+      .await
+      .find_id_mut(&issued.id)
+      .unwrap()
+      .as_mut()
+      .unpack()
+      .storno_id = Some(storno_object.internal_id);
+    enqueue(&processor, &storno_object).await;
+
+    let storno = wait_for(
+      &processor,
+      &storno_object.internal_id,
+      InvoiceStatus::Issued,
+    )
+    .await;
+    let stornoed = wait_for(&processor, &issued.id, InvoiceStatus::Stornoed).await;
+    assert_eq!(stornoed.storno_id, Some(storno.id));
+    assert_eq!(stornoed.storno_invoice_id, storno.invoice_id);
+    assert_eq!(
+      stornoed
+        .status_history
+        .iter()
+        .map(|c| c.status.clone())
+        .collect::<Vec<_>>(),
+      vec![
+        InvoiceStatus::Queued,
+        InvoiceStatus::Submitting,
+        InvoiceStatus::Issued,
+        InvoiceStatus::Stornoed
+      ]
+    );
+    let mock_invoices = mock.invoices();
+    assert_eq!(mock_invoices.len(), 2);
+    assert_eq!(mock_invoices[1].total_gross, -1270.0);
+    assert!(processor.invoice_objects.lock().await.is_empty());
+  }
+
+  /// Agent crashing its worker
+  struct PanicAgent;
+
+  #[tonic::async_trait]
+  impl invoice::InvoiceAgent for PanicAgent {
+    async fn create_invoice(
+      &self,
+      _: invoice::InvoiceObject,
+    ) -> Result<invoice::InvoiceSummary, invoice::AgentError> {
+      panic!("Agent crashed")
+    }
+    async fn find_invoice(
+      &self,
+      _: &str,
+    ) -> Result<Option<invoice::InvoiceSummary>, invoice::AgentError> {
+      panic!("Agent crashed")
+    }
+    async fn find_storno(
+      &self,
+      _: &str,
+    ) -> Result<Option<invoice::InvoiceSummary>, invoice::AgentError> {
+      panic!("Agent crashed")
+    }
+    async fn cancel_invoice(
+      &self,
+      _: invoice::InvoiceObject,
+    ) -> Result<invoice::InvoiceSummary, invoice::AgentError> {
+      panic!("Agent crashed")
+    }
+    async fn register_payment(
+      &self,
+      _: &str,
+      _: invoice::Payment,
+    ) -> Result<(), invoice::AgentError> {
+      panic!("Agent crashed")
+    }
+  }
+
+  #[tokio::test]
+  async fn test_webhook_deliveries() {
+    let (processor, _mock) = test_processor();
+    // Recorded without any status subscriber
+    assert_eq!(processor.status_tx.receiver_count(), 0);
+    let invoice_object = mock::invoice_object();
+    enqueue(&processor, &invoice_object).await;
+    processor.process(invoice_object.clone()).await;
+
+    let deliveries = processor
+      .delivery_log
+      .deliveries
+      .lock()
+      .await
+      .iter()
+      .map(|d| d.unpack().clone())
+      .collect::<Vec<webhook::Delivery>>();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].endpoint, WEBHOOK_ENDPOINT);
+    assert_eq!(deliveries[0].event.event, "invoice.issued");
+    assert_eq!(deliveries[0].event.id, invoice_object.internal_id);
+  }
+
+  #[tokio::test]
+  async fn test_start_returns_on_worker_crash() {
+    let processor = processor_of(PanicAgent);
+    let running = tokio::spawn(processor.clone().start(2));
+    enqueue(&processor, &mock::invoice_object()).await;
+    tokio::time::timeout(Duration::from_secs(5), running)
+      .await
+      .expect("Processor is still running")
+      .unwrap();
+  }
+}
@@ -0,0 +1,5 @@
+// Generated code; not every message is used by this service
+#[allow(dead_code, clippy::all)]
+pub mod invoice {
+  tonic::include_proto!("invoice");
+}
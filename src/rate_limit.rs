@@ -0,0 +1,39 @@
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Shared rate limiter for invoice agent requests
+/// Keeps at least the given interval between two requests,
+/// even if they come from different workers.
+pub struct RateLimiter {
+  interval: Duration,
+  next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+  pub fn new(interval: Duration) -> Self {
+    RateLimiter {
+      interval,
+      next_slot: Mutex::new(Instant::now()),
+    }
+  }
+  /// Create rate limiter from ENV variable
+  /// Default interval is 500ms
+  pub fn from_env() -> Self {
+    let interval_ms = std::env::var("INVOICE_AGENT_MIN_INTERVAL_MS")
+      .ok()
+      .and_then(|v| v.trim().parse::<u64>().ok())
+      .unwrap_or(500);
+    RateLimiter::new(Duration::from_millis(interval_ms))
+  }
+  /// Wait till the next free request slot
+  pub async fn wait(&self) {
+    let slot = {
+      let mut next_slot = self.next_slot.lock().await;
+      let slot = (*next_slot).max(Instant::now());
+      *next_slot = slot + self.interval;
+      slot
+    };
+    tokio::time::sleep_until(slot).await;
+  }
+}
@@ -0,0 +1,165 @@
+use crate::invoice::{AgentError, Failure, Item, PaymentMethod};
+use chrono::{DateTime, Utc};
+use packman::VecPackMember;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[tonic::async_trait]
+pub trait ReceiptAgent {
+  async fn create_receipt(&self, data: Receipt) -> Result<ReceiptSummary, AgentError>;
+  /// Cancel an issued receipt by a storno receipt
+  /// Returns the storno receipt
+  async fn cancel_receipt(&self, receipt_id: &str) -> Result<ReceiptSummary, AgentError>;
+  /// Get an issued receipt with its PDF
+  async fn get_receipt(&self, receipt_id: &str) -> Result<ReceiptSummary, AgentError>;
+  /// Find the receipt issued by the given call ID
+  /// None if there is no such receipt
+  async fn find_receipt(&self, call_id: &str) -> Result<Option<ReceiptSummary>, AgentError>;
+}
+
+#[derive(Debug)]
+pub struct ReceiptSummary {
+  pub receipt_id: String,
+  pub pdf_base64: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum ReceiptStatus {
+  // Receipt is under submission to the receipt agent
+  #[default]
+  Submitting,
+  Issued,
+  // Receipt agent failed to issue the receipt
+  Failed,
+  // Issued receipt is cancelled by a storno receipt
+  Cancelled,
+}
+
+#[derive(Debug)]
+pub enum ReceiptError {
+  WrongStatus(ReceiptStatus),
+}
+
+impl std::fmt::Display for ReceiptError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ReceiptError::WrongStatus(_) => write!(f, "Csak kiállított nyugta sztornózható!"),
+    }
+  }
+}
+
+/// Receipt of a counter sale
+/// Issued synchronously, as the customer is waiting for it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Receipt {
+  pub id: Uuid,
+  pub purchase_id: String,
+  // Receipt number given by the receipt agent
+  pub receipt_id: Option<String>,
+  pub status: ReceiptStatus,
+  pub failure: Option<Failure>,
+  // Receipt number of its storno receipt
+  pub storno_receipt_id: Option<String>,
+  pub payment_method: PaymentMethod,
+  pub items: Vec<Item>,
+  pub total_net: i32,
+  pub total_vat: i32,
+  pub total_gross: i32,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+  pub cancelled_by: Option<u32>,
+  pub cancelled_at: Option<DateTime<Utc>>,
+}
+
+impl Default for Receipt {
+  fn default() -> Self {
+    Receipt {
+      id: Uuid::default(),
+      purchase_id: String::default(),
+      receipt_id: None,
+      status: ReceiptStatus::default(),
+      failure: None,
+      storno_receipt_id: None,
+      payment_method: PaymentMethod::default(),
+      items: Vec::new(),
+      total_net: 0,
+      total_vat: 0,
+      total_gross: 0,
+      created_by: 0,
+      created_at: Utc::now(),
+      cancelled_by: None,
+      cancelled_at: None,
+    }
+  }
+}
+
+impl Receipt {
+  pub fn new(
+    purchase_id: String,
+    payment_method: PaymentMethod,
+    items: Vec<Item>,
+    total_net: i32,
+    total_vat: i32,
+    total_gross: i32,
+    created_by: u32,
+  ) -> Self {
+    Receipt {
+      id: Uuid::new_v4(),
+      purchase_id,
+      receipt_id: None,
+      status: ReceiptStatus::Submitting,
+      failure: None,
+      storno_receipt_id: None,
+      payment_method,
+      items,
+      total_net,
+      total_vat,
+      total_gross,
+      created_by,
+      created_at: Utc::now(),
+      cancelled_by: None,
+      cancelled_at: None,
+    }
+  }
+  /// Call ID we send to the receipt agent,
+  /// so the same receipt cannot be issued twice
+  pub fn call_id(&self) -> String {
+    self.id.to_simple().to_string()
+  }
+  pub fn set_issued(&mut self, receipt_id: String) {
+    self.receipt_id = Some(receipt_id);
+    self.status = ReceiptStatus::Issued;
+    self.failure = None;
+  }
+  pub fn set_failed(&mut self, failure: Failure) {
+    self.status = ReceiptStatus::Failed;
+    self.failure = Some(failure);
+  }
+  /// Receipt agent did not answer, so the receipt might be issued
+  /// It stays under submission with its failure
+  pub fn set_unresolved(&mut self, failure: Failure) {
+    self.status = ReceiptStatus::Submitting;
+    self.failure = Some(failure);
+  }
+  /// Check if the receipt can be cancelled
+  pub fn can_cancel(&self) -> Result<(), ReceiptError> {
+    match self.status {
+      ReceiptStatus::Issued => Ok(()),
+      _ => Err(ReceiptError::WrongStatus(self.status.clone())),
+    }
+  }
+  pub fn set_cancelled(&mut self, storno_receipt_id: String, cancelled_by: u32) {
+    self.storno_receipt_id = Some(storno_receipt_id);
+    self.status = ReceiptStatus::Cancelled;
+    self.cancelled_by = Some(cancelled_by);
+    self.cancelled_at = Some(Utc::now());
+  }
+}
+
+impl VecPackMember for Receipt {
+  type Out = Uuid;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
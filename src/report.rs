@@ -0,0 +1,138 @@
+use crate::invoice::{Invoice, InvoiceKind, InvoiceStatus};
+use chrono::NaiveDate;
+
+/// Unpaid invoice with its due date
+#[derive(Debug, Clone)]
+pub struct Receivable {
+  pub invoice: Invoice,
+  pub payment_duedate: Option<NaiveDate>,
+  pub outstanding: i32,
+  // 0 if it is not yet due
+  pub days_overdue: i64,
+}
+
+/// Unpaid invoices of a customer
+#[derive(Debug, Clone)]
+pub struct CustomerReceivables {
+  pub customer_name: String,
+  pub tax_number: String,
+  pub outstanding: i32,
+  pub overdue: i32,
+  pub receivables: Vec<Receivable>,
+}
+
+/// Unpaid invoices grouped by customer at the given date.
+/// Customers are identified by their tax number if they have any,
+/// otherwise by their name.
+/// Customers with the most overdue amount come first.
+pub fn receivables<'a, I>(
+  invoices: I,
+  today: NaiveDate,
+  overdue_only: bool,
+) -> Vec<CustomerReceivables>
+where
+  I: Iterator<Item = &'a Invoice>,
+{
+  let mut res: Vec<CustomerReceivables> = Vec::new();
+
+  let unpaid = invoices
+    .filter(|i| matches!(i.status, InvoiceStatus::Issued | InvoiceStatus::PdfMissing))
+    .filter(|i| i.kind != InvoiceKind::Proforma)
+    .filter(|i| !i.is_paid() && i.outstanding.is_some());
+
+  for invoice in unpaid {
+    let payment_duedate = invoice.payment_duedate();
+    let days_overdue = payment_duedate
+      .map(|due| (today - due).num_days().max(0))
+      .unwrap_or_default();
+    if overdue_only && days_overdue == 0 {
+      continue;
+    }
+
+    let receivable = Receivable {
+      invoice: invoice.clone(),
+      payment_duedate,
+      outstanding: invoice.outstanding.unwrap_or_default(),
+      days_overdue,
+    };
+
+    let customer = &invoice.customer;
+    let same_customer = |c: &CustomerReceivables| {
+      if !customer.tax_number.is_empty() {
+        c.tax_number == customer.tax_number
+      } else {
+        c.tax_number.is_empty() && c.customer_name == customer.name
+      }
+    };
+    let group = match res.iter().position(same_customer) {
+      Some(index) => &mut res[index],
+      None => {
+        res.push(CustomerReceivables {
+          customer_name: customer.name.clone(),
+          tax_number: customer.tax_number.clone(),
+          outstanding: 0,
+          overdue: 0,
+          receivables: Vec::new(),
+        });
+        res.last_mut().unwrap()
+      }
+    };
+    group.outstanding += receivable.outstanding;
+    if receivable.days_overdue > 0 {
+      group.overdue += receivable.outstanding;
+    }
+    group.receivables.push(receivable);
+  }
+
+  for group in res.iter_mut() {
+    group
+      .receivables
+      .sort_by_key(|r| std::cmp::Reverse(r.days_overdue));
+  }
+  res.sort_by_key(|c| std::cmp::Reverse(c.overdue));
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::invoice::{Customer, Header, InvoiceObject, PaymentMethod};
+  #[test]
+  fn test_receivables() {
+    let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+    let today = date(2021, 3, 10);
+    let invoice = |tax_number: &str, duedate: NaiveDate, outstanding: i32| {
+      let mut invoice: Invoice = InvoiceObject {
+        customer: Customer {
+          name: "Kert Kft.".into(),
+          tax_number: tax_number.into(),
+          ..Customer::default()
+        },
+        header: Header::new(duedate, duedate, duedate, PaymentMethod::Transfer),
+        ..InvoiceObject::default()
+      }
+      .into();
+      invoice.status = InvoiceStatus::Issued;
+      invoice.outstanding = Some(outstanding);
+      invoice
+    };
+    let invoices = [
+      invoice("1234", date(2021, 3, 1), 1000),
+      invoice("1234", date(2021, 3, 20), 500),
+      invoice("5678", date(2021, 3, 1), 0),
+    ];
+
+    let res = receivables(invoices.iter(), today, false);
+    assert_eq!(res.len(), 1);
+    assert_eq!((res[0].outstanding, res[0].overdue), (1500, 1000));
+    assert_eq!(res[0].receivables[0].days_overdue, 9);
+    assert_eq!(
+      res[0].receivables[0].payment_duedate,
+      invoices[0].payment_duedate()
+    );
+    assert_eq!(res[0].receivables[0].invoice.id, invoices[0].id);
+
+    let res = receivables(invoices.iter(), today, true);
+    assert_eq!(res[0].receivables.len(), 1);
+  }
+}
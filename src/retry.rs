@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+/// Retry policy for transient invoice agent failures
+/// Delay is doubled after each failed attempt, till it reaches max_delay
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+  pub max_attempts: u32,
+  pub base_delay: Duration,
+  pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      max_attempts: 5,
+      base_delay: Duration::from_secs(30),
+      max_delay: Duration::from_secs(3600),
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// Create retry policy from ENV variables
+  /// Missing or wrong values fall back to the defaults
+  pub fn from_env() -> Self {
+    let default = RetryPolicy::default();
+    let env_u64 = |key: &str| -> Option<u64> {
+      std::env::var(key)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+    };
+    RetryPolicy {
+      max_attempts: env_u64("INVOICE_RETRY_MAX_ATTEMPTS")
+        .map(|v| v as u32)
+        .unwrap_or(default.max_attempts),
+      base_delay: env_u64("INVOICE_RETRY_BASE_DELAY_SECS")
+        .map(Duration::from_secs)
+        .unwrap_or(default.base_delay),
+      max_delay: env_u64("INVOICE_RETRY_MAX_DELAY_SECS")
+        .map(Duration::from_secs)
+        .unwrap_or(default.max_delay),
+    }
+  }
+  /// Check if we can try again after the given number of attempts
+  pub fn can_retry(&self, attempt_count: u32) -> bool {
+    attempt_count < self.max_attempts
+  }
+  /// Delay before the next attempt
+  /// after the given number of failed attempts
+  pub fn delay(&self, attempt_count: u32) -> Duration {
+    let exp = attempt_count.saturating_sub(1).min(31);
+    self
+      .base_delay
+      .checked_mul(2u32.pow(exp))
+      .unwrap_or(self.max_delay)
+      .min(self.max_delay)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn test_delay() {
+    let policy = RetryPolicy {
+      max_attempts: 5,
+      base_delay: Duration::from_secs(10),
+      max_delay: Duration::from_secs(60),
+    };
+    assert_eq!(policy.delay(1), Duration::from_secs(10));
+    assert_eq!(policy.delay(2), Duration::from_secs(20));
+    assert_eq!(policy.delay(3), Duration::from_secs(40));
+    assert_eq!(policy.delay(4), Duration::from_secs(60));
+    assert_eq!(policy.delay(100), Duration::from_secs(60));
+    assert!(policy.can_retry(4));
+    assert!(!policy.can_retry(5));
+  }
+}
@@ -1,19 +1,62 @@
-use std::env::temp_dir;
-
 use quick_xml::de::{from_str, DeError};
 use quick_xml::se::to_string;
 use serde::{Deserialize, Serialize};
 
+// Timeout of a single szamlazz.hu request
+const REQUEST_TIMEOUT_SECS: u64 = 60;
+
+// szamlazz.hu agent endpoint
+// if INVOICE_AGENT_URL ENV is not set
+const DEFAULT_BASE_URL: &str = "https://www.szamlazz.hu/szamla/";
+
+/// szamlazz.hu response mode of the invoice requests
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseVersion {
+  // Invoice data in szlahu_* headers, and the raw PDF as body
+  Header = 1,
+  // XML body with base64 encoded PDF
+  Xml = 2,
+}
+
+/// Raw szamlazz.hu response without errors
+pub struct AgentResponse {
+  headers: reqwest::header::HeaderMap,
+  body: Vec<u8>,
+}
+
 pub struct SzamlazzHu {
+  // Agent endpoint; a mock server in tests
+  base_url: String,
+  // Response mode of the invoice requests
+  response_version: ResponseVersion,
   agent_key: String,
   invoice_prefix: String,
   bank_name: String,
   bank_account: String,
+  receipt_prefix: String,
+  // Shared by all the requests to reuse the connections
+  client: reqwest::Client,
+}
+
+/// HTTP client of the agent requests
+fn http_client() -> reqwest::Client {
+  reqwest::Client::builder()
+    .timeout(std::time::Duration::from_secs(REQUEST_TIMEOUT_SECS))
+    .build()
+    .expect("Cannot create SzamlazzHu Agent. HTTP client error!")
 }
 
 impl SzamlazzHu {
   pub fn new() -> Self {
     SzamlazzHu {
+      // Set szamlazz.hu agent endpoint from ENV variable
+      base_url: std::env::var("INVOICE_AGENT_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.to_string()),
+      // INVOICE_AGENT_RESPONSE_VERSION=1 sets the lighter header mode
+      // with binary PDF; XML mode otherwise
+      response_version: match std::env::var("INVOICE_AGENT_RESPONSE_VERSION").as_deref() {
+        Ok("1") => ResponseVersion::Header,
+        _ => ResponseVersion::Xml,
+      },
       // Set szamlazz.hu agent key from ENV variable
       agent_key: std::env::var("INVOICE_AGENT_KEY")
         .expect("Cannot create SzamlazzHu Agent. NO AGENT KEY ENV!"),
@@ -26,10 +69,205 @@ impl SzamlazzHu {
       // Set szamlazz.hu agent key from ENV variable
       bank_account: std::env::var("INVOICE_BANK_ACCOUNT")
         .expect("Cannot create SzamlazzHu Agent. NO INVOICE_BANK_ACCOUNT ENV!"),
+      // Set szamlazz.hu receipt prefix from ENV variable
+      receipt_prefix: std::env::var("INVOICE_RECEIPT_PREFIX")
+        .unwrap_or_else(|_| "NYGTA".to_string()),
+      client: http_client(),
+    }
+  }
+
+  /// Agent of the local mock server
+  #[cfg(test)]
+  pub fn mock(addr: std::net::SocketAddr) -> Self {
+    SzamlazzHu {
+      base_url: format!("http://{}/", addr),
+      response_version: ResponseVersion::Xml,
+      agent_key: "mock".into(),
+      invoice_prefix: "GZ".into(),
+      bank_name: "Bank".into(),
+      bank_account: "11111111-22222222".into(),
+      receipt_prefix: "NYGTA".into(),
+      client: http_client(),
+    }
+  }
+
+  /// Issued invoice of the given PDF query
+  /// None if szamlazz.hu does not know it
+  async fn find(
+    &self,
+    mut r: InvoicePdfRequest,
+  ) -> Result<Option<crate::invoice::InvoiceSummary>, crate::invoice::AgentError> {
+    r.set_response_version(self.response_version);
+    let r = r
+      .to_xml()
+      .map_err(|e| crate::invoice::AgentError::DataError(e.to_string()))?;
+
+    let response = match self.post_raw("action-szamla_agent_pdf", &r).await {
+      Ok(response) => response,
+      // szamlazz.hu reports missing invoices as errors
+      Err(crate::invoice::AgentError::NotFound(_, _)) => return Ok(None),
+      Err(e) => return Err(e),
+    };
+
+    self.invoice_summary(response).map(Some)
+  }
+
+  /// Post XML request to szamlazz.hu as the given action
+  /// and return the response body as text
+  async fn post(&self, action: &str, xml: &str) -> Result<String, crate::invoice::AgentError> {
+    let response = self.post_raw(action, xml).await?;
+    Ok(String::from_utf8_lossy(&response.body).to_string())
+  }
+
+  /// Post XML request to szamlazz.hu as the given action
+  /// The XML is sent as a multipart file, named by the action
+  /// Returns the response headers and body
+  async fn post_raw(
+    &self,
+    action: &str,
+    xml: &str,
+  ) -> Result<AgentResponse, crate::invoice::AgentError> {
+    let part = reqwest::multipart::Part::text(xml.to_string())
+      .file_name("request.xml")
+      .mime_str("text/xml")
+      .map_err(|e| crate::invoice::AgentError::InternalError(e.to_string()))?;
+    let form = reqwest::multipart::Form::new().part(action.to_string(), part);
+
+    let response = self
+      .client
+      .post(&self.base_url)
+      .multipart(form)
+      .send()
+      .await
+      .map_err(|e| crate::invoice::AgentError::ConnectionError(e.to_string()))?;
+
+    // Provider side errors and throttling are transient ones
+    if response.status().is_server_error() || response.status().as_u16() == 429 {
+      return Err(crate::invoice::AgentError::ConnectionError(format!(
+        "szamlazz.hu response status: {}",
+        response.status()
+      )));
+    }
+
+    // szamlazz.hu reports its own errors
+    // in the szlahu_error_code and szlahu_error headers,
+    // and in the response body as well
+    let error_code = response.headers().get("szlahu_error_code").map(|c| {
+      c.to_str()
+        .ok()
+        .and_then(|c| c.trim().parse::<i32>().ok())
+        .unwrap_or_default()
+    });
+    let error_message = response
+      .headers()
+      .get("szlahu_error")
+      .and_then(|m| m.to_str().ok())
+      .map(url_decode)
+      .unwrap_or_default();
+
+    let headers = response.headers().clone();
+    let body = response
+      .bytes()
+      .await
+      .map_err(|e| crate::invoice::AgentError::ConnectionError(e.to_string()))?
+      .to_vec();
+
+    match error_code {
+      // Body message is preferred, as the header one may be cut
+      Some(code) => Err(
+        match ErrorResponse::parse(&String::from_utf8_lossy(&body)) {
+          Some(e) if !e.successfull => e.into_error(),
+          _ => provider_error(code, error_message),
+        },
+      ),
+      None => Ok(AgentResponse { headers, body }),
+    }
+  }
+
+  /// Invoice summary of an invoice response by the response version
+  fn invoice_summary(
+    &self,
+    response: AgentResponse,
+  ) -> Result<crate::invoice::InvoiceSummary, crate::invoice::AgentError> {
+    match self.response_version {
+      ResponseVersion::Xml => SzamlazzHuResponse::parse(&String::from_utf8_lossy(&response.body)),
+      ResponseVersion::Header => parse_header_response(&response),
     }
   }
 }
 
+/// Invoice summary of a response version 1
+/// Invoice data is sent in the headers, and the PDF as the body
+fn parse_header_response(
+  response: &AgentResponse,
+) -> Result<crate::invoice::InvoiceSummary, crate::invoice::AgentError> {
+  let header = |name: &str| {
+    response
+      .headers
+      .get(name)
+      .and_then(|v| v.to_str().ok())
+      .map(|v| v.trim().to_string())
+      .filter(|v| !v.is_empty())
+  };
+  let invoice_id = header("szlahu_szamlaszam").ok_or_else(|| {
+    crate::invoice::AgentError::InternalError(
+      "Missing szlahu_szamlaszam in szamlazz.hu response".to_string(),
+    )
+  })?;
+  Ok(crate::invoice::InvoiceSummary {
+    invoice_id,
+    pdf: Some(response.body.to_vec()),
+    outstanding: header("szlahu_kintlevoseg")
+      .and_then(|v| v.parse::<f32>().ok())
+      .map(|v| v.round() as i32),
+    customer_url: header("szlahu_vevoifiokurl").map(|url| url_decode(&url)),
+  })
+}
+
+/// Typed agent error of a szamlazz.hu error code
+/// Unknown codes are kept as provider errors
+pub fn provider_error(code: i32, message: String) -> crate::invoice::AgentError {
+  use crate::invoice::AgentError;
+  match code {
+    // Wrong or disabled agent key
+    3 => AgentError::AuthError(code, message),
+    // Invoice not found; receipt not found
+    7 | 338 => AgentError::NotFound(code, message),
+    // Order number already used; receipt call ID already used
+    259 | 336 => AgentError::Duplicate(code, message),
+    // Wrong customer tax number
+    136 => AgentError::InvalidTaxNumber(code, message),
+    _ => AgentError::ProviderError(code, message),
+  }
+}
+
+/// Decode URL encoded szamlazz.hu header values
+fn url_decode(s: &str) -> String {
+  let bytes = s.as_bytes();
+  let mut res = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'+' => res.push(b' '),
+      b'%' if i + 2 < bytes.len() => {
+        match std::str::from_utf8(&bytes[i + 1..i + 3])
+          .ok()
+          .and_then(|h| u8::from_str_radix(h, 16).ok())
+        {
+          Some(b) => {
+            res.push(b);
+            i += 2;
+          }
+          None => res.push(b'%'),
+        }
+      }
+      b => res.push(b),
+    }
+    i += 1;
+  }
+  String::from_utf8_lossy(&res).to_string()
+}
+
 impl From<crate::invoice::VAT> for VAT {
   fn from(v: crate::invoice::VAT) -> Self {
     match v {
@@ -59,8 +297,11 @@ impl crate::invoice::InvoiceAgent for SzamlazzHu {
     &self,
     data: crate::invoice::InvoiceObject,
   ) -> Result<crate::invoice::InvoiceSummary, crate::invoice::AgentError> {
+    let order_number = data.order_number();
+
     // Create settings object
-    let settings = Settings::new(Some(self.agent_key.clone()));
+    let mut settings = Settings::new(Some(self.agent_key.clone()));
+    settings.set_response_version(self.response_version);
 
     // Create seller object
     let seller = Seller::new(self.bank_name.clone(), self.bank_account.clone());
@@ -71,14 +312,14 @@ impl crate::invoice::InvoiceAgent for SzamlazzHu {
       data.customer.zip,
       data.customer.location,
       data.customer.street,
-      if data.customer.tax_number.len() > 0 {
+      if !data.customer.tax_number.is_empty() {
         Some(data.customer.tax_number)
       } else {
         None
       },
     );
     let waybill = Waybill::new();
-    let header = Header::new(
+    let mut header = Header::new(
       data.header.date_created,
       data.header.date_completion,
       data.header.payment_duedate,
@@ -88,9 +329,33 @@ impl crate::invoice::InvoiceAgent for SzamlazzHu {
         crate::invoice::PaymentMethod::Card => PaymentMethod::CreditCard,
       },
       None,
+      // Our internal ID as order number,
+      // so the invoice can be found later
+      Some(order_number),
       self.invoice_prefix.clone(),
     );
 
+    match data.kind {
+      // Corrective invoice refers to the corrected one
+      crate::invoice::InvoiceKind::Corrective => {
+        let corrected_invoice_id = data.reference_invoice_id.ok_or_else(|| {
+          crate::invoice::AgentError::DataError("Missing invoice ID to correct".to_string())
+        })?;
+        header.set_corrected_invoice(corrected_invoice_id);
+      }
+      crate::invoice::InvoiceKind::Proforma => header.set_proforma(),
+      crate::invoice::InvoiceKind::Deposit => header.set_deposit(),
+      // Deposits are deducted by negative items
+      crate::invoice::InvoiceKind::Final => header.set_final(),
+      // Final invoice of a proforma refers to the proforma
+      crate::invoice::InvoiceKind::Normal => {
+        if let Some(proforma_id) = data.reference_invoice_id {
+          header.set_proforma_id(proforma_id);
+        }
+      }
+      crate::invoice::InvoiceKind::Storno => (),
+    }
+
     // Create item(s) vector
     let items = data
       .items
@@ -110,29 +375,160 @@ impl crate::invoice::InvoiceAgent for SzamlazzHu {
       })
       .collect::<Vec<Item>>();
 
-    let invoice_request = InvoiceRequest::new(settings, header, seller, customer, waybill, items);
-    let r = invoice_request.unwrap();
-    let client = reqwest::Client::new();
+    let r = InvoiceRequest::new(settings, header, seller, customer, waybill, items)
+      .map_err(|e| crate::invoice::AgentError::DataError(e.to_string()))?;
 
-    let response = client
-      .post("https://www.szamlazz.hu/szamla/")
-      .form(&[("action-xmlagentxmlfile", r.as_str())])
-      .send()
+    let response = self.post_raw("action-xmlagentxmlfile", &r).await?;
+
+    self.invoice_summary(response)
+  }
+
+  async fn find_invoice(
+    &self,
+    order_number: &str,
+  ) -> Result<Option<crate::invoice::InvoiceSummary>, crate::invoice::AgentError> {
+    self
+      .find(InvoicePdfRequest::new(
+        self.agent_key.clone(),
+        order_number.to_string(),
+      ))
       .await
-      .map_err(|e| {
-        println!("{:?}", e);
-        crate::invoice::AgentError::DataError(e.to_string())
-      });
+  }
 
-    let text = response?
-      .text()
+  async fn find_storno(
+    &self,
+    order_number: &str,
+  ) -> Result<Option<crate::invoice::InvoiceSummary>, crate::invoice::AgentError> {
+    // Storno invoices have no order number,
+    // so they are sent with it as external ID
+    self
+      .find(InvoicePdfRequest::by_external_id(
+        self.agent_key.clone(),
+        order_number.to_string(),
+      ))
       .await
+  }
+
+  async fn cancel_invoice(
+    &self,
+    data: crate::invoice::InvoiceObject,
+  ) -> Result<crate::invoice::InvoiceSummary, crate::invoice::AgentError> {
+    let order_number = data.order_number();
+    let invoice_id = data.reference_invoice_id.ok_or_else(|| {
+      crate::invoice::AgentError::DataError("Missing invoice ID to storno".to_string())
+    })?;
+
+    let mut settings = Settings::new(Some(self.agent_key.clone()));
+    settings.set_response_version(self.response_version);
+    settings.set_external_id(order_number);
+    let header = StornoHeader::new(
+      invoice_id,
+      data.header.date_created,
+      data.header.date_completion,
+    );
+
+    let r = StornoRequest::new(settings, header)
       .map_err(|e| crate::invoice::AgentError::DataError(e.to_string()))?;
 
-    let response: SzamlazzHuResponse = from_str(text.trim())
-      .map_err(|e| crate::invoice::AgentError::InternalError(e.to_string()))?;
+    let response = self.post_raw("action-szamla_agent_st", &r).await?;
+
+    self.invoice_summary(response)
+  }
+
+  async fn register_payment(
+    &self,
+    invoice_id: &str,
+    payment: crate::invoice::Payment,
+  ) -> Result<(), crate::invoice::AgentError> {
+    let r = PaymentRequest::new(
+      PaymentSettings::new(self.agent_key.clone(), invoice_id.to_string()),
+      PaymentItem::new(
+        payment.date.to_string(),
+        PaymentMethod::from(payment.payment_method),
+        payment.amount,
+      ),
+    )
+    .map_err(|e| crate::invoice::AgentError::DataError(e.to_string()))?;
+
+    // Errors are reported in the response headers
+    self.post("action-szamla_agent_kifiz", &r).await?;
+    Ok(())
+  }
+}
+
+#[tonic::async_trait]
+impl crate::receipt::ReceiptAgent for SzamlazzHu {
+  async fn create_receipt(
+    &self,
+    data: crate::receipt::Receipt,
+  ) -> Result<crate::receipt::ReceiptSummary, crate::invoice::AgentError> {
+    let settings = ReceiptSettings::new(self.agent_key.clone());
+    let header = ReceiptHeader::new(
+      // Call ID prevents issuing the same receipt twice
+      data.call_id(),
+      self.receipt_prefix.clone(),
+      PaymentMethod::from(data.payment_method),
+    );
+    let items = data
+      .items
+      .into_iter()
+      .map(ReceiptItem::from)
+      .collect::<Vec<ReceiptItem>>();
+
+    let r = ReceiptRequest::new(settings, header, items)
+      .map_err(|e| crate::invoice::AgentError::DataError(e.to_string()))?;
+
+    let text = self.post("action-szamla_agent_nyugta_create", &r).await?;
+    ReceiptResponse::parse(&text)
+  }
+
+  async fn cancel_receipt(
+    &self,
+    receipt_id: &str,
+  ) -> Result<crate::receipt::ReceiptSummary, crate::invoice::AgentError> {
+    let r = ReceiptQueryRequest::new(
+      "xmlnyugtast",
+      ReceiptSettings::new(self.agent_key.clone()),
+      ReceiptQueryHeader::by_receipt_id(receipt_id.to_string()),
+    )
+    .map_err(|e| crate::invoice::AgentError::DataError(e.to_string()))?;
 
-    Ok(response.into())
+    let text = self.post("action-szamla_agent_nyugta_storno", &r).await?;
+    ReceiptResponse::parse(&text)
+  }
+
+  async fn get_receipt(
+    &self,
+    receipt_id: &str,
+  ) -> Result<crate::receipt::ReceiptSummary, crate::invoice::AgentError> {
+    let r = ReceiptQueryRequest::new(
+      "xmlnyugtaget",
+      ReceiptSettings::new(self.agent_key.clone()),
+      ReceiptQueryHeader::by_receipt_id(receipt_id.to_string()),
+    )
+    .map_err(|e| crate::invoice::AgentError::DataError(e.to_string()))?;
+
+    let text = self.post("action-szamla_agent_nyugta_get", &r).await?;
+    ReceiptResponse::parse(&text)
+  }
+
+  async fn find_receipt(
+    &self,
+    call_id: &str,
+  ) -> Result<Option<crate::receipt::ReceiptSummary>, crate::invoice::AgentError> {
+    let r = ReceiptQueryRequest::new(
+      "xmlnyugtaget",
+      ReceiptSettings::new(self.agent_key.clone()),
+      ReceiptQueryHeader::by_call_id(call_id.to_string()),
+    )
+    .map_err(|e| crate::invoice::AgentError::DataError(e.to_string()))?;
+
+    let text = self.post("action-szamla_agent_nyugta_get", &r).await?;
+    match ReceiptResponse::parse(&text) {
+      Ok(summary) => Ok(Some(summary)),
+      Err(crate::invoice::AgentError::NotFound(_, _)) => Ok(None),
+      Err(e) => Err(e),
+    }
   }
 }
 
@@ -149,19 +545,97 @@ pub struct SzamlazzHuResponse {
   total_gross: f32,
   #[serde(rename = "kintlevoseg")]
   outstanding: f32,
-  // TODO! Error while deserialize this field. Probably should escape it?
-  // #[serde(rename = "vevoifiokurl")]
-  // invoice_url: String,
+  // vevoifiokurl is taken out before deserializing,
+  // as quick-xml cannot read its CDATA content
   #[serde(rename = "pdf")]
   pdf_blob_base64: String,
 }
 
+impl SzamlazzHuResponse {
+  /// Parse response body to invoice summary
+  /// Error responses are parsed to typed agent errors
+  pub fn parse(text: &str) -> Result<crate::invoice::InvoiceSummary, crate::invoice::AgentError> {
+    if let Some(e) = ErrorResponse::parse(text) {
+      if !e.successfull {
+        return Err(e.into_error());
+      }
+    }
+    let (text, customer_url) = take_element(text, "vevoifiokurl");
+    let response: SzamlazzHuResponse = from_str(text.trim())
+      .map_err(|e| crate::invoice::AgentError::InternalError(e.to_string()))?;
+    let mut summary: crate::invoice::InvoiceSummary = response.into();
+    summary.customer_url = customer_url
+      .map(|url| customer_url_of(&url))
+      .filter(|url| !url.is_empty());
+    Ok(summary)
+  }
+}
+
+/// Remove the given element from the XML text
+/// Returns the rest of the text and the raw element content
+fn take_element(text: &str, name: &str) -> (String, Option<String>) {
+  let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+  match (text.find(&open), text.find(&close)) {
+    (Some(start), Some(end)) if start < end => (
+      format!("{}{}", &text[..start], &text[end + close.len()..]),
+      Some(text[start + open.len()..end].to_string()),
+    ),
+    _ => (text.to_string(), None),
+  }
+}
+
+/// Customer account URL of the raw vevoifiokurl content
+/// It is sent URL encoded or as it is, either one wrapped in CDATA or not
+fn customer_url_of(content: &str) -> String {
+  let content = content.trim();
+  let (url, cdata) = match content
+    .strip_prefix("<![CDATA[")
+    .and_then(|c| c.strip_suffix("]]>"))
+  {
+    Some(url) => (url.trim(), true),
+    None => (content, false),
+  };
+  match url {
+    url if url.starts_with("http%3A") || url.starts_with("https%3A") => url_decode(url),
+    url if cdata => url.to_string(),
+    url => url.replace("&amp;", "&"),
+  }
+}
+
+/// Error part of the invoice and receipt responses
+#[derive(Debug, Deserialize)]
+pub struct ErrorResponse {
+  #[serde(rename = "sikeres")]
+  successfull: bool,
+  #[serde(rename = "hibakod")]
+  error_code: Option<String>,
+  #[serde(rename = "hibauzenet")]
+  error_message: Option<String>,
+}
+
+impl ErrorResponse {
+  /// None if the body is not a szamlazz.hu response
+  pub fn parse(text: &str) -> Option<Self> {
+    from_str(text.trim()).ok()
+  }
+  pub fn into_error(self) -> crate::invoice::AgentError {
+    provider_error(
+      self
+        .error_code
+        .and_then(|c| c.trim().parse::<i32>().ok())
+        .unwrap_or_default(),
+      self.error_message.unwrap_or_default().trim().to_string(),
+    )
+  }
+}
+
 impl From<SzamlazzHuResponse> for crate::invoice::InvoiceSummary {
   fn from(r: SzamlazzHuResponse) -> Self {
     crate::invoice::InvoiceSummary {
       invoice_id: r.invoice_id,
-      pdf_base64: r.pdf_blob_base64,
-      has_error: !r.successfull,
+      pdf: crate::file::base64_decode(&r.pdf_blob_base64.replace("\n", "")).ok(),
+      outstanding: Some(r.outstanding.round() as i32),
+      customer_url: None,
     }
   }
 }
@@ -176,20 +650,376 @@ impl InvoiceRequest {
     _waybill: Waybill,
     items: Vec<Item>,
   ) -> Result<String, DeError> {
-    let s = serialize(&settings)?;
-    let h = serialize(&header)?;
-    let s2 = serialize(&seller)?;
-    let c = serialize(&customer)?;
-    // let w = serialize(&waybill)?;
-    let i = serialize(&items)?;
+    let settings = serialize(&settings)?;
+    let header = serialize(&header)?;
+    let seller = serialize(&seller)?;
+    let customer = serialize(&customer)?;
+    // let waybill = serialize(&waybill)?;
+    let items = serialize(&items)?;
     let intro = r#"<xmlszamla xmlns="http://www.szamlazz.hu/xmlszamla" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.szamlazz.hu/xmlszamla https://www.szamlazz.hu/szamla/docs/xsds/agent/xmlszamla.xsd">"#;
     Ok(format!(
       "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}{}{}{}{}<tetelek>{}</tetelek></xmlszamla>",
-      intro, s, h, s2, c, i
+      intro, settings, header, seller, customer, items
+    ))
+  }
+}
+
+/// Invoice PDF query request
+/// szamlazz.hu finds the invoice by our order number,
+/// or by our external ID if it has no order number
+#[derive(Debug, Serialize)]
+#[serde(rename = "xmlszamlapdf")]
+pub struct InvoicePdfRequest {
+  #[serde(rename = "szamlaagentkulcs")]
+  agent_key: String,
+  #[serde(rename = "valaszVerzio")]
+  response_version: u32,
+  #[serde(rename = "rendelesSzam", skip_serializing_if = "Option::is_none")]
+  order_number: Option<String>,
+  #[serde(rename = "szamlaKulsoAzon", skip_serializing_if = "Option::is_none")]
+  external_id: Option<String>,
+}
+
+impl InvoicePdfRequest {
+  pub fn new(agent_key: String, order_number: String) -> Self {
+    InvoicePdfRequest {
+      agent_key,
+      response_version: 2,
+      order_number: Some(order_number),
+      external_id: None,
+    }
+  }
+  pub fn by_external_id(agent_key: String, external_id: String) -> Self {
+    InvoicePdfRequest {
+      agent_key,
+      response_version: 2,
+      order_number: None,
+      external_id: Some(external_id),
+    }
+  }
+  pub fn set_response_version(&mut self, version: ResponseVersion) {
+    self.response_version = version as u32;
+  }
+  pub fn to_xml(&self) -> Result<String, DeError> {
+    let intro = r#"<xmlszamlapdf xmlns="http://www.szamlazz.hu/xmlszamlapdf" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.szamlazz.hu/xmlszamlapdf https://www.szamlazz.hu/szamla/docs/xsds/agentpdf/xmlszamlapdf.xsd">"#;
+    Ok(format!(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}",
+      serialize(self)?.replacen("<xmlszamlapdf>", intro, 1)
+    ))
+  }
+}
+
+/// Storno invoice request
+/// Cancels the given issued invoice
+pub struct StornoRequest {}
+
+impl StornoRequest {
+  pub fn new(settings: Settings, header: StornoHeader) -> Result<String, DeError> {
+    let settings = serialize(&settings)?;
+    let header = serialize(&header)?;
+    let intro = r#"<xmlszamlast xmlns="http://www.szamlazz.hu/xmlszamlast" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.szamlazz.hu/xmlszamlast https://www.szamlazz.hu/szamla/docs/xsds/agentst/xmlszamlast.xsd">"#;
+    Ok(format!(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}{}{}</xmlszamlast>",
+      intro, settings, header
+    ))
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "fejlec")]
+pub struct StornoHeader {
+  #[serde(rename = "szamlaszam")]
+  invoice_id: String,
+  #[serde(rename = "keltDatum")]
+  date_created: String,
+  #[serde(rename = "teljesitesDatum")]
+  completion_date: String,
+  // SS means storno invoice
+  #[serde(rename = "tipus")]
+  kind: String,
+}
+
+impl StornoHeader {
+  pub fn new(invoice_id: String, date_created: String, completion_date: String) -> Self {
+    StornoHeader {
+      invoice_id,
+      date_created,
+      completion_date,
+      kind: "SS".to_string(),
+    }
+  }
+}
+
+/// Payment request of an issued invoice
+pub struct PaymentRequest {}
+
+impl PaymentRequest {
+  pub fn new(settings: PaymentSettings, payment: PaymentItem) -> Result<String, DeError> {
+    let settings = serialize(&settings)?;
+    let payment = serialize(&payment)?;
+    let intro = r#"<xmlszamlakifiz xmlns="http://www.szamlazz.hu/xmlszamlakifiz" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.szamlazz.hu/xmlszamlakifiz https://www.szamlazz.hu/szamla/docs/xsds/agentkifiz/xmlszamlakifiz.xsd">"#;
+    Ok(format!(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}{}{}</xmlszamlakifiz>",
+      intro, settings, payment
     ))
   }
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename = "beallitasok")]
+pub struct PaymentSettings {
+  #[serde(rename = "szamlaagentkulcs")]
+  agent_key: String,
+  #[serde(rename = "szamlaszam")]
+  invoice_id: String,
+  // Add to the previous payments instead of replacing them
+  #[serde(rename = "additiv")]
+  additive: bool,
+}
+
+impl PaymentSettings {
+  pub fn new(agent_key: String, invoice_id: String) -> Self {
+    PaymentSettings {
+      agent_key,
+      invoice_id,
+      additive: true,
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "kifizetes")]
+pub struct PaymentItem {
+  #[serde(rename = "datum")]
+  date: String,
+  #[serde(rename = "jogcim")]
+  payment_method: String,
+  #[serde(rename = "osszeg")]
+  amount: i32,
+}
+
+impl PaymentItem {
+  pub fn new(date: String, payment_method: PaymentMethod, amount: i32) -> Self {
+    PaymentItem {
+      date,
+      payment_method: payment_method.to_string(),
+      amount,
+    }
+  }
+}
+
+/// Receipt create request
+pub struct ReceiptRequest {}
+
+impl ReceiptRequest {
+  pub fn new(
+    settings: ReceiptSettings,
+    header: ReceiptHeader,
+    items: Vec<ReceiptItem>,
+  ) -> Result<String, DeError> {
+    let settings = serialize(&settings)?;
+    let header = serialize(&header)?;
+    let items = serialize(&items)?;
+    Ok(format!(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}{}{}<tetelek>{}</tetelek></xmlnyugtacreate>",
+      receipt_intro("xmlnyugtacreate"),
+      settings,
+      header,
+      items
+    ))
+  }
+}
+
+/// Receipt storno or get request
+/// Storno finds the receipt by its receipt number,
+/// get by its receipt number or call ID
+pub struct ReceiptQueryRequest {}
+
+impl ReceiptQueryRequest {
+  pub fn new(
+    root: &str,
+    settings: ReceiptSettings,
+    header: ReceiptQueryHeader,
+  ) -> Result<String, DeError> {
+    let settings = serialize(&settings)?;
+    let header = serialize(&header)?;
+    Ok(format!(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}{}{}</{}>",
+      receipt_intro(root),
+      settings,
+      header,
+      root
+    ))
+  }
+}
+
+/// Opening tag of the given receipt request
+fn receipt_intro(root: &str) -> String {
+  format!(
+    r#"<{root} xmlns="http://www.szamlazz.hu/{root}" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://www.szamlazz.hu/{root} http://www.szamlazz.hu/docs/xsds/nyugta/{root}.xsd">"#,
+    root = root
+  )
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "beallitasok")]
+pub struct ReceiptSettings {
+  #[serde(rename = "szamlaagentkulcs")]
+  agent_key: String,
+  #[serde(rename = "pdfLetoltes")]
+  should_download_pdf: bool,
+}
+
+impl ReceiptSettings {
+  pub fn new(agent_key: String) -> Self {
+    ReceiptSettings {
+      agent_key,
+      should_download_pdf: true,
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "fejlec")]
+pub struct ReceiptHeader {
+  #[serde(rename = "hivasAzonosito")]
+  call_id: String,
+  #[serde(rename = "elotag")]
+  prefix: String,
+  #[serde(rename = "fizmod")]
+  payment_method: String,
+  #[serde(rename = "penznem")]
+  currency: String,
+}
+
+impl ReceiptHeader {
+  pub fn new(call_id: String, prefix: String, payment_method: PaymentMethod) -> Self {
+    ReceiptHeader {
+      call_id,
+      prefix,
+      payment_method: payment_method.to_string(),
+      currency: Currency::Huf.to_string(),
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "fejlec")]
+pub struct ReceiptQueryHeader {
+  #[serde(rename = "nyugtaszam", skip_serializing_if = "Option::is_none")]
+  receipt_id: Option<String>,
+  #[serde(rename = "hivasAzonosito", skip_serializing_if = "Option::is_none")]
+  call_id: Option<String>,
+}
+
+impl ReceiptQueryHeader {
+  pub fn by_receipt_id(receipt_id: String) -> Self {
+    ReceiptQueryHeader {
+      receipt_id: Some(receipt_id),
+      call_id: None,
+    }
+  }
+  pub fn by_call_id(call_id: String) -> Self {
+    ReceiptQueryHeader {
+      receipt_id: None,
+      call_id: Some(call_id),
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename = "tetel")]
+pub struct ReceiptItem {
+  #[serde(rename = "megnevezes")]
+  name: String,
+  #[serde(rename = "mennyiseg")]
+  quantity: i32,
+  #[serde(rename = "mennyisegiEgyseg")]
+  unit: String,
+  #[serde(rename = "nettoEgysegar")]
+  net_retail_price: i32,
+  #[serde(rename = "afakulcs")]
+  vat: String,
+  #[serde(rename = "netto")]
+  total_net_price: i32,
+  #[serde(rename = "afa")]
+  total_vat: i32,
+  #[serde(rename = "brutto")]
+  total_gross_price: i32,
+}
+
+impl From<crate::invoice::Item> for ReceiptItem {
+  fn from(i: crate::invoice::Item) -> Self {
+    ReceiptItem {
+      name: i.name,
+      quantity: i.quantity,
+      unit: i.unit,
+      net_retail_price: i.retail_price_net,
+      vat: VAT::from(i.vat).to_string(),
+      total_net_price: i.total_price_net,
+      total_vat: i.total_price_vat,
+      total_gross_price: i.total_price_gross,
+    }
+  }
+}
+
+/// Response of every receipt request
+/// szamlazz.hu reports receipt errors in the response body
+#[derive(Debug, Deserialize)]
+#[serde(rename = "xmlnyugtavalasz")]
+pub struct ReceiptResponse {
+  #[serde(rename = "sikeres")]
+  successfull: bool,
+  #[serde(rename = "hibakod")]
+  error_code: Option<String>,
+  #[serde(rename = "hibauzenet")]
+  error_message: Option<String>,
+  #[serde(rename = "nyugtaPdf")]
+  pdf_base64: Option<String>,
+  #[serde(rename = "nyugta")]
+  receipt: Option<ReceiptResponseData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReceiptResponseData {
+  #[serde(rename = "alap")]
+  base: ReceiptResponseBase,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReceiptResponseBase {
+  #[serde(rename = "nyugtaszam")]
+  receipt_id: String,
+}
+
+impl ReceiptResponse {
+  /// Parse response body to receipt summary
+  pub fn parse(text: &str) -> Result<crate::receipt::ReceiptSummary, crate::invoice::AgentError> {
+    let response: ReceiptResponse = from_str(text.trim())
+      .map_err(|e| crate::invoice::AgentError::InternalError(e.to_string()))?;
+
+    if !response.successfull {
+      return Err(provider_error(
+        response
+          .error_code
+          .and_then(|c| c.trim().parse::<i32>().ok())
+          .unwrap_or_default(),
+        response.error_message.unwrap_or_default(),
+      ));
+    }
+
+    match (response.receipt, response.pdf_base64) {
+      (Some(receipt), Some(pdf_base64)) => Ok(crate::receipt::ReceiptSummary {
+        receipt_id: receipt.base.receipt_id,
+        pdf_base64,
+      }),
+      _ => Err(crate::invoice::AgentError::InternalError(
+        "Missing receipt data in szamlazz.hu response".to_string(),
+      )),
+    }
+  }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename = "beallitasok")]
 pub struct Settings {
@@ -209,6 +1039,9 @@ pub struct Settings {
   response_version: u32,
   #[serde(rename = "aggregator")]
   agregator: Option<String>,
+  // Our own ID of the invoice, to find it later
+  #[serde(rename = "szamlaKulsoAzon", skip_serializing_if = "Option::is_none")]
+  external_id: Option<String>,
 }
 
 impl Settings {
@@ -222,8 +1055,15 @@ impl Settings {
       copy_of_pdf_pages: 2,
       response_version: 2,
       agregator: None,
+      external_id: None,
     }
   }
+  pub fn set_response_version(&mut self, version: ResponseVersion) {
+    self.response_version = version as u32;
+  }
+  pub fn set_external_id(&mut self, external_id: String) {
+    self.external_id = Some(external_id);
+  }
 }
 
 #[derive(Debug, Serialize)]
@@ -273,12 +1113,12 @@ pub enum PaymentMethod {
   Transfer,
 }
 
-impl PaymentMethod {
-  fn to_string(&self) -> String {
+impl std::fmt::Display for PaymentMethod {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
-      PaymentMethod::Cash => String::from("Készpénz"),
-      PaymentMethod::CreditCard => String::from("Bankkártya"),
-      PaymentMethod::Transfer => String::from("Átutalás"),
+      PaymentMethod::Cash => write!(f, "Készpénz"),
+      PaymentMethod::CreditCard => write!(f, "Bankkártya"),
+      PaymentMethod::Transfer => write!(f, "Átutalás"),
     }
   }
 }
@@ -287,10 +1127,10 @@ pub enum Currency {
   Huf,
 }
 
-impl Currency {
-  fn to_string(&self) -> String {
+impl std::fmt::Display for Currency {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
-      Currency::Huf => String::from("HUF"),
+      Currency::Huf => write!(f, "HUF"),
     }
   }
 }
@@ -299,10 +1139,10 @@ pub enum Language {
   Hu,
 }
 
-impl Language {
-  fn to_string(&self) -> String {
+impl std::fmt::Display for Language {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
-      Language::Hu => String::from("hu"),
+      Language::Hu => write!(f, "hu"),
     }
   }
 }
@@ -314,6 +1154,7 @@ impl Header {
     payment_duedate: String,
     payment_method: PaymentMethod,
     comment: Option<String>,
+    order_number: Option<String>,
     invoice_prefix: String,
   ) -> Self {
     Header {
@@ -326,7 +1167,7 @@ impl Header {
       comment,
       exchange_rate_bank: "MNB".to_string(),
       exchange_rate: 0.0,
-      order_number: None,
+      order_number,
       proforma_id: None,
       is_deposit_invoice: false,
       is_final_invoice: false,
@@ -337,6 +1178,27 @@ impl Header {
       template: "Szla8cm".to_string(),
     }
   }
+  /// Set as proforma invoice
+  pub fn set_proforma(&mut self) {
+    self.is_proform_invoice = true;
+  }
+  /// Set as deposit invoice
+  pub fn set_deposit(&mut self) {
+    self.is_deposit_invoice = true;
+  }
+  /// Set as final invoice of deposit invoices
+  pub fn set_final(&mut self) {
+    self.is_final_invoice = true;
+  }
+  /// Set as final invoice of the given proforma invoice
+  pub fn set_proforma_id(&mut self, proforma_id: String) {
+    self.proforma_id = Some(proforma_id);
+  }
+  /// Set as corrective invoice of the given invoice
+  pub fn set_corrected_invoice(&mut self, invoice_id: String) {
+    self.is_corrective_invoice = true;
+    self.corrected_invoce_id = Some(invoice_id);
+  }
 }
 
 #[derive(Debug, Serialize)]
@@ -478,17 +1340,16 @@ pub enum VAT {
   _27,
 }
 
-impl ToString for VAT {
-  fn to_string(&self) -> String {
-    use VAT::*;
+impl std::fmt::Display for VAT {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
-      AAM => "AAM".into(),
-      TAM => "TAM".into(),
-      FAD => "F.AFA".into(),
-      _0 => "0".into(),
-      _5 => "5".into(),
-      _18 => "18".into(),
-      _27 => "27".into(),
+      VAT::AAM => write!(f, "AAM"),
+      VAT::TAM => write!(f, "TAM"),
+      VAT::FAD => write!(f, "F.AFA"),
+      VAT::_0 => write!(f, "0"),
+      VAT::_5 => write!(f, "5"),
+      VAT::_18 => write!(f, "18"),
+      VAT::_27 => write!(f, "27"),
     }
   }
 }
@@ -525,3 +1386,163 @@ where
 {
   to_string(invoice_request)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn test_receipt_response() {
+    let ok = r#"<?xml version="1.0" encoding="UTF-8"?>
+<xmlnyugtavalasz xmlns="http://www.szamlazz.hu/xmlnyugtavalasz">
+  <sikeres>true</sikeres>
+  <hibakod></hibakod>
+  <hibauzenet></hibauzenet>
+  <nyugtaPdf>SlZCRVJpMHhMalFL</nyugtaPdf>
+  <nyugta>
+    <alap>
+      <id>1</id>
+      <hivasAzonosito>abc</hivasAzonosito>
+      <nyugtaszam>NYGTA-2021-1</nyugtaszam>
+      <tipus>NY</tipus>
+      <stornozott>false</stornozott>
+    </alap>
+  </nyugta>
+</xmlnyugtavalasz>"#;
+    let summary = ReceiptResponse::parse(ok).unwrap();
+    assert_eq!(summary.receipt_id, "NYGTA-2021-1");
+    assert_eq!(summary.pdf_base64, "SlZCRVJpMHhMalFL");
+
+    let failed = r#"<?xml version="1.0" encoding="UTF-8"?>
+<xmlnyugtavalasz xmlns="http://www.szamlazz.hu/xmlnyugtavalasz">
+  <sikeres>false</sikeres>
+  <hibakod>338</hibakod>
+  <hibauzenet>Nem található nyugta</hibauzenet>
+</xmlnyugtavalasz>"#;
+    match ReceiptResponse::parse(failed) {
+      Err(crate::invoice::AgentError::NotFound(code, _)) => assert_eq!(code, 338),
+      r => panic!("Unexpected result: {:?}", r),
+    }
+  }
+
+  #[test]
+  fn test_invoice_response() {
+    let response = |url: &str| {
+      format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<xmlszamlavalasz xmlns="http://www.szamlazz.hu/xmlszamlavalasz">
+  <sikeres>true</sikeres>
+  <szamlaszam>GZ-2021-1</szamlaszam>
+  <szamlanetto>1000</szamlanetto>
+  <szamlabrutto>1270</szamlabrutto>
+  <kintlevoseg>1270</kintlevoseg>
+  <vevoifiokurl>{}</vevoifiokurl>
+  <pdf>SlZCRVJpMHhMalFL</pdf>
+</xmlszamlavalasz>"#,
+        url
+      )
+    };
+    let url = "https://www.szamlazz.hu/szamla/?page=vevoifiok&partnerguid=abc";
+
+    let summary = SzamlazzHuResponse::parse(&response(
+      "https%3A%2F%2Fwww.szamlazz.hu%2Fszamla%2F%3Fpage%3Dvevoifiok%26partnerguid%3Dabc",
+    ))
+    .unwrap();
+    assert_eq!(summary.customer_url.as_deref(), Some(url));
+    assert_eq!(summary.outstanding, Some(1270));
+    assert_eq!(summary.pdf.as_deref(), Some(&b"JVBERi0xLjQK"[..]));
+
+    let summary = SzamlazzHuResponse::parse(&response(&format!("<![CDATA[{}]]>", url))).unwrap();
+    assert_eq!(summary.customer_url.as_deref(), Some(url));
+
+    let summary = SzamlazzHuResponse::parse(&response(
+      "<![CDATA[https%3A%2F%2Fwww.szamlazz.hu%2Fszamla%2F%3Fpage%3Dvevoifiok%26partnerguid%3Dabc]]>",
+    ))
+    .unwrap();
+    assert_eq!(summary.customer_url.as_deref(), Some(url));
+  }
+
+  #[test]
+  fn test_error_response() {
+    let failed = r#"<?xml version="1.0" encoding="UTF-8"?>
+<xmlszamlavalasz xmlns="http://www.szamlazz.hu/xmlszamlavalasz">
+  <sikeres>false</sikeres>
+  <hibakod>136</hibakod>
+  <hibauzenet>Hibás adószám</hibauzenet>
+</xmlszamlavalasz>"#;
+    match SzamlazzHuResponse::parse(failed) {
+      Err(crate::invoice::AgentError::InvalidTaxNumber(code, message)) => {
+        assert_eq!((code, message.as_str()), (136, "Hibás adószám"))
+      }
+      r => panic!("Unexpected result: {:?}", r),
+    }
+    assert!(!provider_error(999, String::new()).is_retryable());
+    assert_eq!(provider_error(999, String::new()).error_code(), Some(999));
+    assert_eq!(
+      url_decode("Hib%C3%A1s+ad%C3%B3sz%C3%A1m%"),
+      "Hibás adószám%"
+    );
+  }
+
+  #[tokio::test]
+  async fn test_mock_agent() {
+    use crate::invoice::InvoiceAgent;
+    use crate::mock::MockMode;
+    let (addr, mock) = crate::mock::start(([127, 0, 0, 1], 0).into()).unwrap();
+    let agent = SzamlazzHu::mock(addr);
+    let invoice_object = crate::mock::invoice_object();
+
+    let summary = agent.create_invoice(invoice_object.clone()).await.unwrap();
+    assert!(summary.invoice_id.starts_with("GZ-"));
+    assert_eq!(summary.outstanding, Some(1270));
+    assert!(summary
+      .customer_url
+      .unwrap()
+      .starts_with("https://www.szamlazz.hu/szamla/?"));
+    assert_eq!(summary.pdf.unwrap()[..5], b"%PDF-"[..]);
+
+    // Same order number again
+    match agent.create_invoice(invoice_object.clone()).await {
+      Err(crate::invoice::AgentError::Duplicate(code, _)) => assert_eq!(code, 259),
+      r => panic!("Unexpected result: {:?}", r),
+    }
+    assert!(agent
+      .find_invoice(&invoice_object.order_number())
+      .await
+      .unwrap()
+      .is_some());
+
+    mock.set_mode(MockMode::Error(3, "Sikertelen bejelentkezés".into()));
+    match agent.create_invoice(invoice_object.clone()).await {
+      Err(crate::invoice::AgentError::AuthError(code, _)) => assert_eq!(code, 3),
+      r => panic!("Unexpected result: {:?}", r),
+    }
+
+    mock.set_mode(MockMode::Malformed);
+    assert!(agent.create_invoice(invoice_object.clone()).await.is_err());
+    assert_eq!(mock.invoices().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_mock_agent_header_mode() {
+    use crate::invoice::InvoiceAgent;
+    let (addr, _mock) = crate::mock::start(([127, 0, 0, 1], 0).into()).unwrap();
+    let agent = SzamlazzHu {
+      response_version: ResponseVersion::Header,
+      ..SzamlazzHu::mock(addr)
+    };
+    let invoice_object = crate::mock::invoice_object();
+    let order_number = invoice_object.order_number();
+
+    let summary = agent.create_invoice(invoice_object).await.unwrap();
+    assert!(summary.invoice_id.starts_with("GZ-"));
+    assert_eq!(summary.outstanding, Some(1270));
+    assert!(summary
+      .customer_url
+      .unwrap()
+      .starts_with("https://www.szamlazz.hu/szamla/?"));
+    assert_eq!(summary.pdf.unwrap()[..5], b"%PDF-"[..]);
+
+    let found = agent.find_invoice(&order_number).await.unwrap().unwrap();
+    assert_eq!(found.invoice_id, summary.invoice_id);
+  }
+}
@@ -0,0 +1,431 @@
+use crate::invoice::{Failure, Invoice, InvoiceKind, InvoiceStatus};
+use crate::retry::RetryPolicy;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use packman::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use uuid::Uuid;
+
+// Header of the request body signature
+pub const SIGNATURE_HEADER: &str = "X-Invoice-Signature";
+// Header of the signing time; part of the signed payload
+pub const TIMESTAMP_HEADER: &str = "X-Invoice-Timestamp";
+
+/// Webhook configuration
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+  // Every event is delivered to every endpoint
+  pub endpoints: Vec<String>,
+  // HMAC-SHA256 key of the signatures
+  pub secret: String,
+  pub retry_policy: RetryPolicy,
+  pub timeout: Duration,
+}
+
+impl WebhookConfig {
+  /// Create webhook config from ENV variables
+  /// INVOICE_WEBHOOK_URLS is a comma separated list of endpoints
+  /// INVOICE_WEBHOOK_SECRET is required if there is any endpoint
+  pub fn from_env() -> Self {
+    let endpoints: Vec<String> = std::env::var("INVOICE_WEBHOOK_URLS")
+      .unwrap_or_default()
+      .split(',')
+      .map(|url| url.trim().to_string())
+      .filter(|url| !url.is_empty())
+      .collect();
+    // Deliveries are never signed with an empty key
+    let secret = match std::env::var("INVOICE_WEBHOOK_SECRET") {
+      Ok(secret) if !secret.trim().is_empty() => secret,
+      _ if endpoints.is_empty() => String::default(),
+      _ => panic!("Cannot create webhook notifier. NO INVOICE_WEBHOOK_SECRET ENV!"),
+    };
+    let max_attempts = std::env::var("INVOICE_WEBHOOK_MAX_ATTEMPTS")
+      .ok()
+      .and_then(|v| v.trim().parse::<u32>().ok())
+      .unwrap_or(10);
+    WebhookConfig {
+      endpoints,
+      secret,
+      retry_policy: RetryPolicy {
+        max_attempts,
+        ..RetryPolicy::default()
+      },
+      timeout: Duration::from_secs(10),
+    }
+  }
+}
+
+/// Invoice event sent to the webhook endpoints as JSON
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WebhookEvent {
+  // invoice.issued or invoice.failed
+  pub event: String,
+  pub id: Uuid,
+  pub purchase_id: String,
+  pub invoice_id: Option<String>,
+  pub kind: InvoiceKind,
+  pub status: InvoiceStatus,
+  pub failure: Option<Failure>,
+  pub created_at: DateTime<Utc>,
+}
+
+impl WebhookEvent {
+  /// Event of the invoice status change from the given previous status
+  /// None if the subscribers are not interested in it.
+  /// Invoice is issued once it gets its invoice number,
+  /// even if its PDF is saved only later.
+  pub fn from_change(previous: &InvoiceStatus, i: &Invoice) -> Option<Self> {
+    let event = match (previous, &i.status) {
+      (previous, next) if previous == next => return None,
+      (InvoiceStatus::PdfMissing, InvoiceStatus::Issued) => return None,
+      (_, InvoiceStatus::Issued) | (_, InvoiceStatus::PdfMissing) => "invoice.issued",
+      (_, InvoiceStatus::Failed) => "invoice.failed",
+      _ => return None,
+    };
+    Some(WebhookEvent {
+      event: event.to_string(),
+      id: i.id,
+      purchase_id: i.purchase_id.clone(),
+      invoice_id: i.invoice_id.clone(),
+      kind: i.kind.clone(),
+      status: i.status.clone(),
+      failure: i.failure.clone(),
+      created_at: Utc::now(),
+    })
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum DeliveryStatus {
+  #[default]
+  Pending,
+  Delivered,
+  // Every attempt failed; can be replayed
+  Failed,
+}
+
+/// Delivery of an event to an endpoint
+/// Persisted, so the delivery log survives restarts
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Delivery {
+  pub id: Uuid,
+  pub endpoint: String,
+  pub event: WebhookEvent,
+  pub status: DeliveryStatus,
+  pub attempt_count: u32,
+  pub last_attempt_at: Option<DateTime<Utc>>,
+  pub next_attempt_at: Option<DateTime<Utc>>,
+  pub last_error: Option<String>,
+  pub delivered_at: Option<DateTime<Utc>>,
+}
+
+impl Delivery {
+  pub fn new(endpoint: String, event: WebhookEvent) -> Self {
+    Delivery {
+      id: Uuid::new_v4(),
+      endpoint,
+      event,
+      ..Delivery::default()
+    }
+  }
+  /// Check if it is time for the next attempt
+  pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+    self.status == DeliveryStatus::Pending && self.next_attempt_at.is_none_or(|next| next <= now)
+  }
+  pub fn set_delivered(&mut self) {
+    self.attempt_count += 1;
+    self.last_attempt_at = Some(Utc::now());
+    self.next_attempt_at = None;
+    self.status = DeliveryStatus::Delivered;
+    self.delivered_at = self.last_attempt_at;
+  }
+  /// Schedule the next attempt by the retry policy,
+  /// or set it failed if there is no attempt left
+  pub fn set_attempt_failed(&mut self, error: String, retry_policy: &RetryPolicy) {
+    self.attempt_count += 1;
+    self.last_attempt_at = Some(Utc::now());
+    self.last_error = Some(error);
+    if retry_policy.can_retry(self.attempt_count) {
+      let delay = retry_policy.delay(self.attempt_count);
+      self.next_attempt_at = Some(
+        Utc::now() + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero()),
+      );
+    } else {
+      self.next_attempt_at = None;
+      self.status = DeliveryStatus::Failed;
+    }
+  }
+  /// Deliver a failed delivery again from scratch
+  /// Returns false if it is not failed
+  pub fn replay(&mut self) -> bool {
+    if self.status != DeliveryStatus::Failed {
+      return false;
+    }
+    self.status = DeliveryStatus::Pending;
+    self.attempt_count = 0;
+    self.next_attempt_at = None;
+    true
+  }
+}
+
+impl VecPackMember for Delivery {
+  type Out = Uuid;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.id
+  }
+}
+
+/// Hex encoded HMAC-SHA256 signature of the timestamp and the body
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+  let mut mac =
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+  mac.update(format!("{}.{}", timestamp, body).as_bytes());
+  mac
+    .finalize()
+    .into_bytes()
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+/// Post the signed event to the endpoint
+/// Any non 2xx response is an error
+pub async fn send(
+  client: &reqwest::Client,
+  endpoint: &str,
+  secret: &str,
+  event: &WebhookEvent,
+) -> Result<(), String> {
+  let body = serde_json::to_string(event).map_err(|e| e.to_string())?;
+  let timestamp = Utc::now().timestamp();
+  let res = client
+    .post(endpoint)
+    .header("Content-Type", "application/json")
+    .header(TIMESTAMP_HEADER, timestamp.to_string())
+    .header(
+      SIGNATURE_HEADER,
+      format!("sha256={}", sign(secret, timestamp, &body)),
+    )
+    .body(body)
+    .send()
+    .await
+    .map_err(|e| e.to_string())?;
+  match res.status() {
+    s if s.is_success() => Ok(()),
+    s => Err(format!("Webhook endpoint responded {}", s)),
+  }
+}
+
+/// Persisted webhook deliveries
+/// Deliveries are recorded where the invoice status changes are saved,
+/// so no event is lost even if the notifier is behind or not running.
+pub struct DeliveryLog {
+  // Every event is delivered to every endpoint
+  pub endpoints: Vec<String>,
+  pub deliveries: Arc<Mutex<VecPack<Delivery>>>,
+  // Notified when there is a new or replayed delivery
+  pub notify: Arc<Notify>,
+}
+
+impl DeliveryLog {
+  pub fn new(
+    endpoints: Vec<String>,
+    deliveries: Arc<Mutex<VecPack<Delivery>>>,
+    notify: Arc<Notify>,
+  ) -> Self {
+    DeliveryLog {
+      endpoints,
+      deliveries,
+      notify,
+    }
+  }
+
+  /// Save a delivery of the invoice status change for each endpoint
+  pub async fn record(&self, previous: &InvoiceStatus, i: &Invoice) {
+    let event = match WebhookEvent::from_change(previous, i) {
+      Some(event) => event,
+      None => return,
+    };
+    if self.endpoints.is_empty() {
+      return;
+    }
+    let mut deliveries = self.deliveries.lock().await;
+    for endpoint in &self.endpoints {
+      if let Err(e) = deliveries.insert(Delivery::new(endpoint.to_string(), event.clone())) {
+        error!("Error while saving webhook delivery: {}; {}", i.id, e);
+      }
+    }
+    drop(deliveries);
+    self.notify.notify_one();
+  }
+}
+
+/// Delivers the invoice events to the webhook endpoints
+pub struct WebhookNotifier {
+  config: WebhookConfig,
+  client: reqwest::Client,
+  log: Arc<DeliveryLog>,
+  // Check the delivery log at least this often
+  poll_interval: Duration,
+}
+
+impl WebhookNotifier {
+  pub fn new(config: WebhookConfig, log: Arc<DeliveryLog>, poll_interval: Duration) -> Self {
+    let client = reqwest::Client::builder()
+      .timeout(config.timeout)
+      .build()
+      .expect("Error while building webhook HTTP client");
+    WebhookNotifier {
+      config,
+      client,
+      log,
+      poll_interval,
+    }
+  }
+
+  /// Deliver the due deliveries of the delivery log.
+  /// Runs forever.
+  pub async fn start(self: Arc<Self>) {
+    loop {
+      for delivery in self.due_deliveries().await {
+        self.deliver(delivery).await;
+      }
+      let _ = tokio::time::timeout(self.poll_interval, self.log.notify.notified()).await;
+    }
+  }
+
+  async fn due_deliveries(&self) -> Vec<Delivery> {
+    let now = Utc::now();
+    self
+      .log
+      .deliveries
+      .lock()
+      .await
+      .iter()
+      .filter(|d| d.unpack().is_due(now))
+      .map(|d| d.unpack().clone())
+      .collect()
+  }
+
+  async fn deliver(&self, delivery: Delivery) {
+    let res = send(
+      &self.client,
+      &delivery.endpoint,
+      &self.config.secret,
+      &delivery.event,
+    )
+    .await;
+    if let Err(e) = &res {
+      error!("Webhook delivery error: {}; {}", delivery.id, e);
+    }
+    match self.log.deliveries.lock().await.find_id_mut(&delivery.id) {
+      Ok(d) => match res {
+        Ok(_) => d.as_mut().unpack().set_delivered(),
+        Err(e) => d
+          .as_mut()
+          .unpack()
+          .set_attempt_failed(e, &self.config.retry_policy),
+      },
+      Err(e) => error!("Webhook delivery not found: {}; {}", delivery.id, e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
+
+  #[tokio::test]
+  async fn test_send() {
+    // Local HTTP stand-in of a webhook endpoint
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}/hook", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+      let (mut socket, _) = listener.accept().await.unwrap();
+      let mut request = Vec::new();
+      let mut buf = [0u8; 4096];
+      // Read till the whole JSON body is arrived
+      while !request.ends_with(b"}") {
+        let n = socket.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+      }
+      socket
+        .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+        .await
+        .unwrap();
+      String::from_utf8(request).unwrap()
+    });
+
+    let event = WebhookEvent {
+      event: "invoice.issued".into(),
+      invoice_id: Some("E-GZ-2021-1".into()),
+      ..WebhookEvent::default()
+    };
+    let client = reqwest::Client::new();
+    assert!(send(&client, &endpoint, "secret", &event).await.is_ok());
+
+    let request = server.await.unwrap();
+    let (head, body) = request.split_once("\r\n\r\n").unwrap();
+    let header = |name: &str| {
+      head
+        .lines()
+        .find_map(|l| l.strip_prefix(&format!("{}: ", name.to_lowercase())))
+        .unwrap()
+        .to_string()
+    };
+    let timestamp = header(TIMESTAMP_HEADER).parse::<i64>().unwrap();
+    assert_eq!(
+      header(SIGNATURE_HEADER),
+      format!("sha256={}", sign("secret", timestamp, body))
+    );
+    let received: WebhookEvent = serde_json::from_str(body).unwrap();
+    assert_eq!(received.invoice_id, event.invoice_id);
+  }
+
+  #[test]
+  fn test_event_from_change() {
+    use InvoiceStatus::*;
+    let event = |previous: InvoiceStatus, status: InvoiceStatus| {
+      let invoice = Invoice {
+        status,
+        ..Invoice::default()
+      };
+      WebhookEvent::from_change(&previous, &invoice).map(|e| e.event)
+    };
+    assert_eq!(event(Submitting, Issued).as_deref(), Some("invoice.issued"));
+    assert_eq!(
+      event(Submitting, PdfMissing).as_deref(),
+      Some("invoice.issued")
+    );
+    // Issued event is sent already
+    assert_eq!(event(PdfMissing, Issued), None);
+    assert_eq!(event(PdfMissing, PdfMissing), None);
+    assert_eq!(event(Submitting, Failed).as_deref(), Some("invoice.failed"));
+    assert_eq!(event(Queued, Failed).as_deref(), Some("invoice.failed"));
+    assert_eq!(event(Submitting, Queued), None);
+  }
+
+  #[test]
+  fn test_delivery_retry() {
+    let policy = RetryPolicy {
+      max_attempts: 2,
+      ..RetryPolicy::default()
+    };
+    let mut delivery = Delivery::new("http://localhost".into(), WebhookEvent::default());
+    assert!(delivery.is_due(Utc::now()));
+    delivery.set_attempt_failed("timeout".into(), &policy);
+    assert_eq!(delivery.status, DeliveryStatus::Pending);
+    assert!(!delivery.is_due(Utc::now()));
+    delivery.set_attempt_failed("timeout".into(), &policy);
+    assert_eq!(delivery.status, DeliveryStatus::Failed);
+    assert!(delivery.replay());
+    assert!(delivery.is_due(Utc::now()));
+  }
+}